@@ -1,5 +1,7 @@
 use std::process::exit;
 
+use rgb_core::cpu::Dispatch;
+
 
 fn main(){
     let args: Vec<String> = std::env::args().collect();
@@ -9,21 +11,25 @@ fn main(){
         exit(-1);
     }
 
-    let cart = rgb_core::cart::Cart::load(&args[1], None).unwrap();
-    let mut dmg = rgb_core::Dmg::new(cart);
-
-
-    let cycles = 40_000_000;
-    let start = std::time::Instant::now();
-    let mut frames = 0;
-
-    for _ in 0..cycles {
-        if dmg.step() {
-            frames += 1;
+    // Decoding every opcode when executed is the baseline the dispatch tables
+    // are compared to
+    for (name, dispatch) in [("match", Dispatch::Match), ("table", Dispatch::Table)] {
+        let cart = rgb_core::cart::Cart::load(&args[1], None).unwrap();
+        let mut dmg = rgb_core::Dmg::new(cart);
+        dmg.cpu.set_dispatch(dispatch);
+
+        let cycles = 40_000_000;
+        let start = std::time::Instant::now();
+        let mut frames = 0;
+
+        for _ in 0..cycles {
+            if dmg.step().unwrap() {
+                frames += 1;
+            }
         }
-    }
 
-    let runtime = std::time::Instant::now() - start;
+        let runtime = std::time::Instant::now() - start;
 
-    println!("Runtime: {:?}, fps: {}", runtime, (frames as f64) / runtime.as_secs_f64() );
-}
\ No newline at end of file
+        println!("{} dispatch: runtime: {:?}, fps: {}", name, runtime, (frames as f64) / runtime.as_secs_f64() );
+    }
+}
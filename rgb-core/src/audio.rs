@@ -1,9 +1,15 @@
+use crate::savestate::{StateLoadError, StateReader, StateWriter};
+
 pub struct Audio {
     pub audio_buffer: Vec<i16>,
     registers: [u8; REGISTER_LENGTH],
 
-    sample_timer: f64,
+    // Time since the last sample, in 1/SAMPLE_TIMER_SCALE cycle units
+    sample_timer: usize,
     current_cycle: usize,
+    // Cycle of the next frame sequencer step, and the step number
+    next_frame_step: usize,
+    frame_step: u8,
 
     // Sound generators
     square1: SquareGenerator,
@@ -24,15 +30,23 @@ const NR52: u16 = 0xff26;
 
 const REGISTER_LENGTH: usize = 0x40;
 
-const SAMPLE_PERIOD: f64 =  (95.0 * 739.2) / 735.0;
+// A sample every (95 * 739.2) / 735 = 3344/35 cycles, the sample timer counts
+// in 1/35 cycles to keep it exact
+const SAMPLE_PERIOD: usize = 3344;
+const SAMPLE_TIMER_SCALE: usize = 35;
+
+// The frame sequencer clocks the length counters, sweep and envelopes at 512Hz
+const FRAME_STEP_PERIOD: usize = 8192;
 
 impl Audio {
     pub(crate) fn new() -> Audio {
         Audio{
             audio_buffer: Vec::new(),
             registers: [0; REGISTER_LENGTH],
-            sample_timer: 0.0,
+            sample_timer: 0,
             current_cycle: 0,
+            next_frame_step: FRAME_STEP_PERIOD,
+            frame_step: 0,
             square1: SquareGenerator::new(true),
             square2: SquareGenerator::new(false),
             wave: WaveGenerator::new(),
@@ -42,7 +56,7 @@ impl Audio {
     pub(crate) fn read(&self, address: u16) -> u8 {
         let address = address as usize - 0xff10;
         assert!(address < REGISTER_LENGTH);
-        self.registers[address as usize]
+        self.registers[address]
     }
 
     pub(crate) fn write(&mut self, address: u16, data: u8) {
@@ -50,7 +64,7 @@ impl Audio {
             _ if address < NR20 => self.square1.set_register(address - NR10, data),
             _ if address < NR30 => self.square2.set_register(address - NR20, data),
             _ if address < _NR40 => self.wave.set_register(address - NR30, data),
-            _ if address >= 0xff30 && address < 0xff40 => self.wave.set_sample(address - 0xff30, data),
+            _ if (0xff30..0xff40).contains(&address) => self.wave.set_sample(address - 0xff30, data),
 
 
             NR52 => println!("Write NR52 {}", data),
@@ -58,27 +72,70 @@ impl Audio {
         }
     }
 
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_usize(self.sample_timer);
+        state.write_usize(self.current_cycle);
+        state.write_usize(self.next_frame_step);
+        state.write_u8(self.frame_step);
+        self.square1.save_state(state);
+        self.square2.save_state(state);
+        self.wave.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        state.read_bytes_into(&mut self.registers)?;
+        self.sample_timer = state.read_usize()?;
+        self.current_cycle = state.read_usize()?;
+        self.next_frame_step = state.read_usize()?;
+        self.frame_step = state.read_u8()?;
+        self.square1.load_state(state)?;
+        self.square2.load_state(state)?;
+        self.wave.load_state(state)
+    }
+
+    /// Cycle of the next sample, the generators are only run when they are
+    /// sampled or written to
+    pub(crate) fn next_event(&self) -> usize {
+        self.current_cycle + self.cycles_to_sample()
+    }
+
+    // Run the generators up to `cycle`, from one frame sequencer step or
+    // sample to the next
     pub(crate) fn step(&mut self, cycle: usize) {
 
         while self.current_cycle < cycle {
-            self.square1.step(self.current_cycle);
-            self.square2.step(self.current_cycle);
-            self.wave.step(self.current_cycle);
-    
-            self.sample_timer += 1.0;
-            self.current_cycle += 1;
-    
-            let sample = self.square1.sample as i16 +
-                              self.square2.sample as i16 +
-                              self.wave.sample as i16;
-    
-            while self.sample_timer > SAMPLE_PERIOD {
+            if self.current_cycle == self.next_frame_step {
+                self.square1.frame_step(self.frame_step);
+                self.square2.frame_step(self.frame_step);
+                self.wave.frame_step(self.frame_step);
+                self.frame_step = (self.frame_step + 1) % 8;
+                self.next_frame_step += FRAME_STEP_PERIOD;
+            }
+
+            let end = cycle.min(self.next_frame_step).min(self.current_cycle + self.cycles_to_sample());
+            self.square1.step(self.current_cycle, end);
+            self.square2.step(self.current_cycle, end);
+            self.wave.step(self.current_cycle, end);
+
+            self.sample_timer += (end - self.current_cycle) * SAMPLE_TIMER_SCALE;
+            self.current_cycle = end;
+
+            if self.sample_timer > SAMPLE_PERIOD {
+                let sample = self.square1.sample as i16 +
+                                  self.square2.sample as i16 +
+                                  self.wave.sample as i16;
                 self.audio_buffer.push(sample * 255);
                 self.sample_timer -= SAMPLE_PERIOD;
             }
         }
         
     }
+
+    // Cycles until the next sample is taken, at the end of the last one
+    fn cycles_to_sample(&self) -> usize {
+        SAMPLE_PERIOD.saturating_sub(self.sample_timer) / SAMPLE_TIMER_SCALE + 1
+    }
 }
 
 // Square generator
@@ -109,10 +166,6 @@ struct SquareGenerator {
     frequency_timer: usize,
     frequency_timer_last_cycle: usize,
     sample: u8,
-
-    // Times
-    last_frame_step_cycle: usize,
-    frame_counter: u8,
 }
 
 impl SquareGenerator {
@@ -142,94 +195,144 @@ impl SquareGenerator {
                 self.length_enable = (data & 0x40) != 0;
                 self.trigger = (data & 0x80) != 0;
             },
-            _ => assert!(false, "Trying to write on unimplemented register"),
+            _ => panic!("Trying to write on unimplemented register"),
         }
 
         // dbg!(&self);
     }
 
-    fn step(&mut self, cycle: usize) {
-        if self.trigger {
-            self.enable = true;
-            // self.volume = 15;
-            self.trigger = false;
-        }
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enable);
+        state.write_u16(self.frequency);
+        state.write_bool(self.has_sweep);
+        state.write_u8(self.sweep_period);
+        state.write_bool(self.sweep_neg);
+        state.write_u8(self.sweep_shift);
+        state.write_u8(self.duty);
+        state.write_u8(self.length);
+        state.write_bool(self.length_enable);
+        state.write_u8(self.volume);
+        state.write_bool(self.envelope_add);
+        state.write_u8(self.envelope_period);
+        state.write_u8(self.envelope_timer);
+        state.write_bool(self.trigger);
+        state.write_u8(self.square_step);
+        state.write_usize(self.frequency_timer);
+        state.write_usize(self.frequency_timer_last_cycle);
+        state.write_u8(self.sample);
+    }
 
-        if self.enable {
-
-            // Frame sequencer  
-            if self.last_frame_step_cycle + 2048 < cycle {
-                // Length
-                if self.frame_counter % 2 == 0 {
-                    if self.length_enable && self.length > 0 {
-                        self.length -= 1;
-                        if self.length == 0 {
-                            self.enable = false;
-                        }
-                    }
-                }
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        self.enable = state.read_bool()?;
+        self.frequency = state.read_u16()?;
+        self.has_sweep = state.read_bool()?;
+        self.sweep_period = state.read_u8()?;
+        self.sweep_neg = state.read_bool()?;
+        self.sweep_shift = state.read_u8()?;
+        self.duty = state.read_u8()?;
+        self.length = state.read_u8()?;
+        self.length_enable = state.read_bool()?;
+        self.volume = state.read_u8()?;
+        self.envelope_add = state.read_bool()?;
+        self.envelope_period = state.read_u8()?;
+        self.envelope_timer = state.read_u8()?;
+        self.trigger = state.read_bool()?;
+        self.square_step = state.read_u8()?;
+        self.frequency_timer = state.read_usize()?;
+        self.frequency_timer_last_cycle = state.read_usize()?;
+        self.sample = state.read_u8()?;
+        Ok(())
+    }
 
-                // Volume
-                if self.frame_counter == 7 {
-                    if self.envelope_period != 0 {
-                        if self.envelope_timer >= self.envelope_period {
-                            self.envelope_timer = 0;
-                            // dbg!(self.volume);
-                            // assert!(false);
-                            if self.envelope_add && self.volume != 15 {
-                                self.volume += 1;
-                            }
-                            if !self.envelope_add && self.volume != 0 {
-                                self.volume -= 1;
-                            }
-                        } else {
-                            self.envelope_timer += 1;
-                        }
-                    }
-                }
+    fn frame_step(&mut self, step: u8) {
+        if !self.enable {
+            return;
+        }
 
-                // Sweep
-                if self.frame_counter == 2 || self.frame_counter == 6 {
-                    // Sweep
-                }
+        // Length
+        if step.is_multiple_of(2) && self.length_enable && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enable = false;
+            }
+        }
 
-                self.frame_counter += 1;
-                if self.frame_counter > 7 {
-                    self.frame_counter = 0;
+        // Volume
+        if step == 7 && self.envelope_period != 0 {
+            if self.envelope_timer >= self.envelope_period {
+                self.envelope_timer = 0;
+                if self.envelope_add && self.volume != 15 {
+                    self.volume += 1;
+                }
+                if !self.envelope_add && self.volume != 0 {
+                    self.volume -= 1;
                 }
+            } else {
+                self.envelope_timer += 1;
             }
+        }
 
-            // Square generations
-            if self.frequency_timer_last_cycle != 0 {
-                self.frequency_timer += cycle - self.frequency_timer_last_cycle;
-                let period = (2048 - self.frequency as usize) * 4;
-
-                if self.frequency_timer >= period {
-                    self.frequency_timer -= period;
-                    self.square_step += 1;
-                    if self.square_step > 7 {
-                        self.square_step = 0;
-                    }
-                }
+        // Sweep
+        if step == 2 || step == 6 {
+            // Sweep
+        }
+    }
 
-                let state = match self.duty {
-                    0 => self.square_step >= 1,
-                    1 => self.square_step >= 2,
-                    2 => self.square_step >= 4,
-                    3 => self.square_step >= 6,
-                    _ => unimplemented!(),
-                };
-                self.sample = if state { self.volume } else { 0 };
-                // if state {
-                //     dbg!(self.sample);
-                // }
-            }
-            
-            self.frequency_timer_last_cycle = cycle;
-        } else {
+    // Run the cycles from `start` to `end`, the sample is the output at the
+    // last one
+    fn step(&mut self, start: usize, end: usize) {
+        if self.trigger {
+            self.enable = true;
+            // self.volume = 15;
+            self.trigger = false;
+        }
+
+        if !self.enable {
             self.sample = 0;
+            return;
         }
 
+        // The timer only starts at the cycle after the first one run
+        let mut cycle = start;
+        while self.frequency_timer_last_cycle == 0 && cycle < end {
+            self.frequency_timer_last_cycle = cycle;
+            cycle += 1;
+        }
+        if cycle == end {
+            return;
+        }
+
+        // Square generations
+        let period = (2048 - self.frequency as usize) * 4;
+        // The timer catches up at once with the cycles spent disabled, it
+        // then moves by one step per cycle until it is back under the period
+        let mut timer = self.frequency_timer + cycle - self.frequency_timer_last_cycle;
+        let mut steps = 0;
+        if timer >= period {
+            timer -= period;
+            steps += 1;
+        }
+        let cycles = end - cycle - 1;
+        let catch_up = (timer / (period - 1)).min(cycles);
+        timer -= catch_up * (period - 1);
+        steps += catch_up;
+        if catch_up < cycles {
+            timer += cycles - catch_up;
+            steps += timer / period;
+            timer %= period;
+        }
+        self.frequency_timer = timer;
+        self.square_step = ((self.square_step as usize + steps) % 8) as u8;
+        self.frequency_timer_last_cycle = end - 1;
+
+        let state = match self.duty {
+            0 => self.square_step >= 1,
+            1 => self.square_step >= 2,
+            2 => self.square_step >= 4,
+            3 => self.square_step >= 6,
+            _ => unimplemented!(),
+        };
+        self.sample = if state { self.volume } else { 0 };
     }
 
 }
@@ -239,9 +342,6 @@ impl SquareGenerator {
 struct WaveGenerator {
     enable: bool,
 
-    last_frame_step_cycle: usize,
-    frame_counter: u8,
-
     dac_power: bool,
     length: u8,
     length_enable: bool,
@@ -289,7 +389,52 @@ impl WaveGenerator {
         self.samples[(2*index)+1] = data&0x0f;
     }
 
-    fn step(&mut self, cycle: usize) {
+    fn save_state(&self, state: &mut StateWriter) {
+        state.write_bool(self.enable);
+        state.write_bool(self.dac_power);
+        state.write_u8(self.length);
+        state.write_bool(self.length_enable);
+        state.write_u8(self.volume_code);
+        state.write_u16(self.frequency);
+        state.write_bool(self.trigger);
+        state.write_bytes(&self.samples);
+        state.write_usize(self.sample_index);
+        state.write_usize(self.timer);
+        state.write_usize(self.timer_period);
+        state.write_usize(self.current_cycle);
+        state.write_u8(self.sample);
+    }
+
+    fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        self.enable = state.read_bool()?;
+        self.dac_power = state.read_bool()?;
+        self.length = state.read_u8()?;
+        self.length_enable = state.read_bool()?;
+        self.volume_code = state.read_u8()?;
+        self.frequency = state.read_u16()?;
+        self.trigger = state.read_bool()?;
+        state.read_bytes_into(&mut self.samples)?;
+        self.sample_index = state.read_usize()?;
+        self.timer = state.read_usize()?;
+        self.timer_period = state.read_usize()?;
+        self.current_cycle = state.read_usize()?;
+        self.sample = state.read_u8()?;
+        Ok(())
+    }
+
+    fn frame_step(&mut self, step: u8) {
+        // Length
+        if self.enable && step.is_multiple_of(2) && self.length_enable && self.length > 0 {
+            self.length -= 1;
+            if self.length == 0 {
+                self.enable = false;
+            }
+        }
+    }
+
+    // Run the cycles from `start` to `end`, the generator lags one cycle
+    // behind and catches up at once with the cycles spent disabled
+    fn step(&mut self, _start: usize, end: usize) {
 
         if self.trigger {
             self.enable = true;
@@ -298,40 +443,58 @@ impl WaveGenerator {
             self.timer_period = (2048 - self.frequency as usize) * 2;
         }
 
-        if self.enable {
-            // Frame sequencer  
-            if self.last_frame_step_cycle + 2048 < cycle {
-                // Length
-                if self.frame_counter % 2 == 0 {
-                    if self.length_enable && self.length > 0 {
-                        self.length -= 1;
-                        if self.length == 0 {
-                            self.enable = false;
-                        }
-                    }
-                }
-            }
+        let cycle = end - 1;
+        if !self.enable || self.current_cycle >= cycle {
+            return;
+        }
 
-            while self.current_cycle < cycle {
-                self.timer += 1;
-                if self.timer >= self.timer_period {
-
-                    self.sample = self.samples[self.sample_index] >> match self.volume_code {
-                        0 => 4,
-                        1 => 0,
-                        2 => 1,
-                        3 => 2,
-                        _ => unreachable!(),
-                    };
-
-                    self.timer = 0;
-                    self.sample_index += 1;
-                    if self.sample_index > 31 {
-                        self.sample_index = 0;
-                    }
-                }
-                self.current_cycle += 1;
-            }
+        // The timer restarts from 0 at every sample, the first one comes
+        // right away when the period was shortened under the timer
+        let ticks = cycle - self.current_cycle;
+        self.current_cycle = cycle;
+        let first = self.timer_period.saturating_sub(self.timer).max(1);
+        if ticks < first {
+            self.timer += ticks;
+            return;
         }
+        let samples = 1 + (ticks - first) / self.timer_period;
+        self.timer = (ticks - first) % self.timer_period;
+
+        let index = (self.sample_index + samples - 1) % 32;
+        self.sample = self.samples[index] >> match self.volume_code {
+            0 => 4,
+            1 => 0,
+            2 => 1,
+            3 => 2,
+            _ => unreachable!(),
+        };
+        self.sample_index = (index + 1) % 32;
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn frame_sequencer_rate() {
+        let mut audio = Audio::new();
+        // Square 1 with a length of 4, clocked at 256Hz, and a volume
+        // envelope decreasing every other 64Hz clock
+        audio.write(0xff11, 0x04);
+        audio.write(0xff12, 0xf1);
+        audio.write(0xff14, 0xc0);
+
+        audio.step(3*2*FRAME_STEP_PERIOD);
+        assert!(audio.square1.enable);
+        assert_eq!(audio.square1.length, 1);
+        audio.step(4*2*FRAME_STEP_PERIOD);
+        assert!(!audio.square1.enable);
+
+        // Without length the envelope runs on, from the next 64Hz clock
+        audio.write(0xff14, 0x80);
+        audio.step(3*8*FRAME_STEP_PERIOD);
+        assert_eq!(audio.square1.volume, 15);
+        audio.step(3*8*FRAME_STEP_PERIOD + 1);
+        assert_eq!(audio.square1.volume, 14);
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,83 @@
+// Memory bus seen by the CPU
+//
+// The CPU only accesses memory through the `Bus` trait. `Mem` implements the
+// Gameboy memory map, other implementations allow to run the CPU against a
+// different memory, for example a flat RAM to test instructions.
+
+const IF_ADDRESS: u16 = 0xff0f;
+const IE_ADDRESS: u16 = 0xffff;
+
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+    fn write(&mut self, address: u16, data: u8);
+    /// Read memory without any side effect on the emulation, for debuggers
+    fn peek(&self, address: u16) -> u8;
+
+    /// Called by the CPU with the number of clock cycles elapsed since the
+    /// previous call, to keep the devices behind the bus in sync: one M-cycle
+    /// (4 clock cycles) before every memory access, and the internal cycles
+    /// remaining at the end of an instruction.
+    fn tick(&mut self, cycles: usize) {
+        let _ = cycles;
+    }
+
+    /// Interrupts both enabled and requested (IE & IF)
+    fn pending_interrupts(&self) -> u8;
+    /// Clear the request flag of an interrupt being serviced
+    fn acknowledge_interrupt(&mut self, interrupt: u8);
+}
+
+/// Flat 64KiB RAM, with IE and IF at their usual addresses
+pub struct FlatRam {
+    pub memory: Vec<u8>,
+    /// Clock cycles ticked by the CPU
+    pub cycles: usize,
+}
+
+impl FlatRam {
+    pub fn new() -> FlatRam {
+        FlatRam {
+            memory: vec![0; 0x10000],
+            cycles: 0,
+        }
+    }
+
+    /// Create a RAM with `data` loaded at address 0
+    pub fn create_from_slice(data: &[u8]) -> FlatRam {
+        let mut ram = FlatRam::new();
+        ram.memory[..data.len()].copy_from_slice(data);
+        ram
+    }
+}
+
+impl Default for FlatRam {
+    fn default() -> FlatRam {
+        FlatRam::new()
+    }
+}
+
+impl Bus for FlatRam {
+    fn read(&mut self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.memory[address as usize] = data;
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles;
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.memory[IE_ADDRESS as usize] & self.memory[IF_ADDRESS as usize] & 0x1f
+    }
+
+    fn acknowledge_interrupt(&mut self, interrupt: u8) {
+        self.memory[IF_ADDRESS as usize] &= !interrupt;
+    }
+}
@@ -0,0 +1,144 @@
+// Shadow call stack
+//
+// Tracks the calls made by the CPU (CALL, RST and interrupt dispatches) and
+// their returns (RET, RETI), to print a backtrace when the emulation fails.
+//
+// Games do not always use the stack in pairs of calls and returns: a function
+// can drop its return address (POP, LD SP, ADD SP) or replace it, and RET is
+// sometimes used as an indirect jump. These are detected by comparing the stack
+// pointer and the popped address with the recorded frames: a frame is dropped
+// when SP goes above its return address, and a return not matching the
+// innermost frame is counted as a mismatch.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Write};
+
+use crate::symbols::Symbols;
+
+/// Deepest call stack tracked, the outermost frames are dropped beyond it
+const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Call,
+    Rst,
+    Interrupt,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// Address of the call instruction, or of the interrupted instruction
+    pub call_site: u16,
+    /// Called function
+    pub target: u16,
+    pub return_address: u16,
+    /// Stack address of the return address
+    pub sp: u16,
+    /// ROM bank mapped when the call was made
+    pub rom_bank: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct CallStack {
+    frames: VecDeque<Frame>,
+    mismatches: usize,
+    calls: u64,
+    changes: u64,
+}
+
+impl CallStack {
+    pub fn new() -> CallStack {
+        CallStack::default()
+    }
+
+    /// Frames from the outermost to the innermost
+    pub fn frames(&self) -> &VecDeque<Frame> {
+        &self.frames
+    }
+
+    /// Calls recorded since the creation of the call stack, the innermost
+    /// frames are the latest ones
+    pub fn calls(&self) -> u64 {
+        self.calls
+    }
+
+    /// Incremented on every change of the frames, to detect them without
+    /// comparing the frames
+    pub fn changes(&self) -> u64 {
+        self.changes
+    }
+
+    /// Returns and stack manipulations that did not match the recorded frames
+    pub fn mismatches(&self) -> usize {
+        self.mismatches
+    }
+
+    pub fn clear(&mut self) {
+        self.frames.clear();
+        self.changes += 1;
+    }
+
+    pub(crate) fn call(&mut self, frame: Frame) {
+        if self.frames.len() == MAX_DEPTH {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+        self.calls += 1;
+        self.changes += 1;
+    }
+
+    /// A return popped `return_address` from the stack at `sp`
+    pub(crate) fn ret(&mut self, sp: u16, return_address: u16) {
+        self.unwind(sp);
+        match self.frames.back() {
+            Some(frame) if frame.sp == sp => {
+                if frame.return_address != return_address {
+                    self.mismatches += 1;
+                }
+                self.frames.pop_back();
+                self.changes += 1;
+            },
+            // Return to an address pushed by the program
+            _ => self.mismatches += 1,
+        }
+    }
+
+    /// Drop the frames whose return address is above the stack pointer
+    pub(crate) fn unwind(&mut self, sp: u16) {
+        while self.frames.back().is_some_and(|frame| frame.sp < sp) {
+            self.frames.pop_back();
+            self.mismatches += 1;
+            self.changes += 1;
+        }
+    }
+
+    /// Backtrace from the innermost frame, `pc` being the current address
+    pub fn format(&self, pc: u16, rom_bank: usize, symbols: Option<&Symbols>) -> String {
+        let describe = |address: u16, bank: usize| match symbols.and_then(|symbols| symbols.lookup(address, bank)) {
+            Some(location) => format!("${:04x} <{}>", address, location),
+            None => format!("${:04x}", address),
+        };
+
+        let mut backtrace = String::new();
+        let (mut address, mut bank) = (pc, rom_bank);
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            writeln!(backtrace, "#{:<3} {} in {}{}", depth, describe(address, bank), describe(frame.target, frame.rom_bank),
+                     if frame.kind == FrameKind::Interrupt { " (interrupt)" } else { "" }).unwrap();
+            address = frame.call_site;
+            bank = frame.rom_bank;
+        }
+        writeln!(backtrace, "#{:<3} {}", self.frames.len(), describe(address, bank)).unwrap();
+        backtrace
+    }
+}
+
+impl fmt::Display for FrameKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            FrameKind::Call => "CALL",
+            FrameKind::Rst => "RST",
+            FrameKind::Interrupt => "interrupt",
+        })
+    }
+}
@@ -4,6 +4,11 @@ use std::io::prelude::*;
 use std::fs::File;
 use std::fmt;
 
+use crate::error::EmulationError;
+use crate::rtc::{self, Rtc};
+use crate::savestate::{StateLoadError, StateReader, StateWriter};
+use crate::scheduler::NEVER;
+
 pub struct Cart {
     pub rom: Vec<u8>,
     pub ram: Vec<u8>,
@@ -14,14 +19,34 @@ pub struct Cart {
     type_str: &'static str,
     ram_data_mask: u8,
     ram_addr_mask: u16,
+    // MBC1 wired for multicarts (MBC1M), with a 4-bit lower bank register
+    multicart: bool,
 
     // Cart runtime state
     ram_enable: bool,
     ram_banking_mode: bool,
     rom_bank: usize,
     ram_bank: usize,
+    // ROM bank mapped at 0x0000-0x3fff
+    rom_bank0: usize,
+    // MBC1 bank registers, the banks are derived from them and the mode
+    bank_low: usize,
+    bank_high: usize,
+
+    // MBC3 real-time clock, selected by the RAM banks 0x08 to 0x0C
+    rtc: Option<Rtc>,
+
+    // Error raised by the last access, taken by the emulator
+    error: Option<EmulationError>,
 }
 
+// Nintendo logo of the cart header, at 0x104
+const LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
 #[derive(Debug)]
 pub struct CartLoadError {
     pub error: String,
@@ -61,24 +86,36 @@ impl Cart {
         Cart::init(buffer, ram_buffer)
     }
 
+    /// Create a cart from a ROM image, the image is padded to the minimum
+    /// 32KiB ROM size if it is shorter
     pub fn create_from_slice(slice: &[u8]) -> Cart {
-        Cart::init(slice.to_vec(), None).unwrap()
+        let mut rom = slice.to_vec();
+        if rom.len() < 0x8000 {
+            rom.resize(0x8000, 0);
+        }
+        Cart::init(rom, None).unwrap()
     }
 
     pub fn read(&self, address:u16) -> u8 {
         let bank_offset = self.rom_bank*0x4000;
         let ram_offset = self.ram_bank*0x2000;
         match address {
-            _ if address < 0x4000 => if (address as usize) < self.rom.len() { self.rom[address as usize] } else { 0xff },
-            _ if address < 0x8000 => self.rom[bank_offset + ((address&0x3fff) as usize)],
-            _ if (0xA000..0xC000).contains(&address) => {
-                    if self.ram_size != 0 {self.ram[ram_offset + ((address&0x1fff) as usize)]} else {0}
-                }
+            _ if address < 0x4000 => *self.rom.get(self.rom_bank0*0x4000 + address as usize).unwrap_or(&0xff),
+            _ if address < 0x8000 => *self.rom.get(bank_offset + (address&0x3fff) as usize).unwrap_or(&0xff),
+            // Disabled or missing RAM reads as an open bus
+            _ if (0xA000..0xC000).contains(&address) && !self.ram_enable => 0xff,
+            _ if (0xA000..0xC000).contains(&address) => match &self.rtc {
+                Some(rtc) if self.ram_bank >= 0x08 => rtc.read(self.ram_bank),
+                _ => if self.ram_size != 0 {
+                    // Unimplemented data bits read as 1s (MBC2 4-bit RAM)
+                    self.ram[ram_offset + (((address&self.ram_addr_mask)&0x1fff) as usize)] | !self.ram_data_mask
+                } else {0xff},
+            }
             _ => { println!("Warning: Reading outside the rom!"); 0 }
         }
     }
 
-    // Implements MBC1 mapper only for now
+    // Writes to the ROM area go to the mapper registers
     pub fn write(&mut self, address:u16, data:u8) {
 
 
@@ -96,33 +133,33 @@ impl Cart {
             Type::MBC1 => {
                 match address & 0x6000 {
                     0x0000 => self.ram_enable = data&0x0f == 0x0a,
-                    0x2000 => {
-                        self.rom_bank = (if data==0 {1} else {self.rom_bank} & 0x60) | (data&0x1f) as usize
-                    }
-                    0x4000 => {
-                        if self.ram_banking_mode {
-                            self.ram_bank = (data & 0x03) as usize;
-                        } else {
-                            self.rom_bank = (self.rom_bank & 0x1f) | ((data & 0x03)<<5) as usize;
-                        }
-                    }
-                    0x6000 => {
-                        if (data & 0x01) == 0 {
-                            self.ram_banking_mode = false;
-                            self.rom_bank = (self.rom_bank & 0x1f) | (self.ram_bank << 5);
-                            self.ram_bank = 0;
-                        } else {
-                            self.ram_banking_mode = true;
-                            self.ram_bank = (self.rom_bank & 0x60) >> 5;
-                            self.rom_bank &= 0x1f;
-                        }
-                    },
+                    0x2000 => self.bank_low = if data&0x1f == 0 {1} else {(data&0x1f) as usize},
+                    0x4000 => self.bank_high = (data&0x03) as usize,
+                    0x6000 => self.ram_banking_mode = data&0x01 != 0,
                     _ => (),
                 }
+                self.update_mbc1_banks();
             }
             Type::MBC2 => {
-                self.ram_enable = true;
-                if address & 0x6000 == 0x2000 { self.rom_bank = (data & 0x0F) as usize }
+                // Address bit 8 selects the register, in the lower 16KiB only
+                match address & 0x4100 {
+                    0x0000 => self.ram_enable = data&0x0f == 0x0a,
+                    0x0100 => self.rom_bank = if data&0x0f == 0 {1} else {(data&0x0f) as usize},
+                    _ => (),
+                }
+            }
+            Type::MBC3 => {
+                match address & 0x6000 {
+                    0x0000 => self.ram_enable = data&0x0f == 0x0a,
+                    0x2000 => self.rom_bank = if data&0x7f == 0 {1} else {(data&0x7f) as usize},
+                    0x4000 => match data {
+                        0x00..=0x03 => self.ram_bank = data as usize,
+                        0x08..=0x0C if self.rtc.is_some() => self.ram_bank = data as usize,
+                        _ => (),
+                    },
+                    0x6000 => if let Some(rtc) = &mut self.rtc { rtc.latch(data) },
+                    _ => (),
+                }
             }
             Type::MBC5 => {
                 match address & 0x7000 {
@@ -133,18 +170,151 @@ impl Cart {
                     _ => (),
                 }
             }
-            _ => panic!("Cart mapper type not supported: {}", self.type_str),
+            _ => self.error = Some(EmulationError::UnsupportedMapper(self.type_str)),
+        }
+        self.mask_banks();
+    }
+
+    // Bank registers wider than the ROM or RAM size wrap around, as the
+    // upper bank lines are not connected
+    fn mask_banks(&mut self) {
+        let rom_banks = self.rom.len().div_ceil(0x4000).max(1);
+        self.rom_bank %= rom_banks;
+        self.rom_bank0 %= rom_banks;
+
+        // The MBC3 clock registers are selected as RAM banks 0x08 to 0x0C
+        if self.rtc.is_none() || self.ram_bank < 0x08 {
+            self.ram_bank %= (self.ram_size/0x2000).max(1);
+        }
+    }
+
+    // The upper bank register extends the ROM bank in mode 0, and selects the
+    // RAM bank and the ROM bank mapped at 0x0000 in mode 1
+    fn update_mbc1_banks(&mut self) {
+        // MBC1M carts do not wire the upper bit of the lower register
+        let (bank_low, shift) = if self.multicart { (self.bank_low&0x0f, 4) } else { (self.bank_low, 5) };
+        self.rom_bank = self.bank_high << shift | bank_low;
+        if self.ram_banking_mode {
+            self.rom_bank0 = self.bank_high << shift;
+            self.ram_bank = self.bank_high;
+        } else {
+            self.rom_bank0 = 0;
+            self.ram_bank = 0;
         }
     }
 
     fn write_ram(&mut self, address: u16, data: u8) {
         let ram_offset = self.ram_bank * 0x2000;
 
+        if let Some(rtc) = &mut self.rtc {
+            if self.ram_bank >= 0x08 {
+                if self.ram_enable {
+                    rtc.write(self.ram_bank, data);
+                }
+                return;
+            }
+        }
+
         if self.ram_size != 0 && self.ram_enable {
             self.ram[ram_offset + (((address&self.ram_addr_mask)&0x1fff) as usize)] = data & self.ram_data_mask;
         }
     }
 
+    /// Take the error raised by a cart access, if any
+    pub fn take_error(&mut self) -> Option<EmulationError> {
+        self.error.take()
+    }
+
+    /// Content of the save file: the cart RAM, followed by the clock footer
+    /// for carts with a clock
+    pub fn save_data(&self) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &self.rtc {
+            data.extend(rtc.footer());
+        }
+        data
+    }
+
+    /// Real-time clock of MBC3 carts with a timer
+    pub fn rtc(&self) -> Option<&Rtc> {
+        self.rtc.as_ref()
+    }
+
+    pub fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+
+    /// Run the cart clock up to `cycle`
+    pub(crate) fn step(&mut self, cycle: usize) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.step(cycle);
+        }
+    }
+
+    /// Cycle of the next clock event
+    pub(crate) fn next_event(&self) -> usize {
+        self.rtc.as_ref().map_or(NEVER, Rtc::next_event)
+    }
+
+    /// ROM bank currently mapped at 0x4000-0x7fff
+    pub fn rom_bank(&self) -> usize {
+        self.rom_bank
+    }
+
+    /// Offset in the ROM image of a ROM address in the current mapping
+    pub fn rom_offset(&self, address: u16) -> Option<usize> {
+        let offset = match address {
+            0x0000..=0x3fff => self.rom_bank0*0x4000 + address as usize,
+            0x4000..=0x7fff => self.rom_bank*0x4000 + (address&0x3fff) as usize,
+            _ => return None,
+        };
+        (offset < self.rom.len()).then_some(offset)
+    }
+
+    /// Offset in the cart RAM of a RAM address in the current mapping
+    pub fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !(0xA000..0xC000).contains(&address) || (self.rtc.is_some() && self.ram_bank >= 0x08) {
+            return None;
+        }
+        let offset = self.ram_bank*0x2000 + ((address&self.ram_addr_mask)&0x1fff) as usize;
+        (offset < self.ram.len()).then_some(offset)
+    }
+
+    /// Identifier of the loaded ROM, built from the header checksums
+    pub fn rom_id(&self) -> u32 {
+        let header = |address: usize| *self.rom.get(address).unwrap_or(&0) as u32;
+        (header(0x14D) << 16) | (header(0x14E) << 8) | header(0x14F)
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.ram);
+        state.write_bool(self.ram_enable);
+        state.write_bool(self.ram_banking_mode);
+        state.write_usize(self.rom_bank);
+        state.write_usize(self.ram_bank);
+        state.write_usize(self.rom_bank0);
+        state.write_usize(self.bank_low);
+        state.write_usize(self.bank_high);
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(state);
+        }
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        state.read_bytes_into(&mut self.ram)?;
+        self.ram_enable = state.read_bool()?;
+        self.ram_banking_mode = state.read_bool()?;
+        self.rom_bank = state.read_usize()?;
+        self.ram_bank = state.read_usize()?;
+        self.rom_bank0 = state.read_usize()?;
+        self.bank_low = state.read_usize()?;
+        self.bank_high = state.read_usize()?;
+        match &mut self.rtc {
+            Some(rtc) => rtc.load_state(state),
+            None => Ok(()),
+        }
+    }
+
     // Private functions
     fn init(buffer: Vec<u8>, ram_buffer: Option<Vec<u8>>) -> Result<Cart, CartLoadError> {
 
@@ -162,7 +332,7 @@ impl Cart {
             0x0C => (Type::MMM01,  true , false, false, false, "MMM01+RAM"),
             0x0D => (Type::MMM01,  true , true , false, false, "MMM01+RAM+BATTERY"),
             0x0F => (Type::MBC3,   false, true , true , false, "MBC3+TIMER+BATTERY"),
-            0x10 => (Type::MBC3,   false, true , true , false, "MBC3+TIMER+RAM+BATTERY"),
+            0x10 => (Type::MBC3,   true , true , true , false, "MBC3+TIMER+RAM+BATTERY"),
             0x11 => (Type::MBC3,   false, false, false, false, "MBC3"),
             0x12 => (Type::MBC3,   true , false, false, false, "MBC3+RAM"),
             0x13 => (Type::MBC3,   true , true , false, false, "MBC3+RAM+BATTERY"),
@@ -183,6 +353,12 @@ impl Cart {
         };
 
         let has_ram = decoded_type.1;
+        let has_timer = decoded_type.3;
+
+        // Multicarts are made of several games of 256KiB, each with its header
+        let multicart = matches!(decoded_type.0, Type::MBC1) &&
+                        (1..buffer.len()/0x40000).any(|game| buffer[game*0x40000 + 0x104..].starts_with(&LOGO));
+        let type_str = if multicart { "MBC1M (multicart)" } else { decoded_type.5 };
 
         let ram;
         let ram_size;
@@ -193,10 +369,13 @@ impl Cart {
                 1 => 2*1024,
                 2 => 8*1024,
                 3 => 32*1024,
+                4 => 128*1024,
+                5 => 64*1024,
                 _ => 0,
             };
             ram_data_mask = 0xff;
-            ram_addr_mask = (ram_size - 1) as u16;
+            // RAM smaller than a bank is echoed in the bank
+            ram_addr_mask = (ram_size.clamp(1, 0x2000) - 1) as u16;
         } else if let Type::MBC2 = decoded_type.0 {
             ram_size = 512;
             ram_addr_mask = 0x01ff;
@@ -207,16 +386,23 @@ impl Cart {
             ram_data_mask = 0x0;
         }
 
-        if let Some(ram_buffer) = ram_buffer {
-            if ram_buffer.len() != ram_size {
-                return Err(CartLoadError::from("Ram save file has wrong size."));
+        let mut rtc = has_timer.then(Rtc::new);
+
+        if let Some(mut ram_buffer) = ram_buffer {
+            // Carts with a clock can have it saved after the RAM
+            match (&mut rtc, ram_buffer.len().checked_sub(ram_size)) {
+                (_, Some(0)) => (),
+                (Some(rtc), Some(rtc::FOOTER_LENGTH | rtc::SHORT_FOOTER_LENGTH)) => {
+                    rtc.load_footer(&ram_buffer.split_off(ram_size)).map_err(|error| CartLoadError { error })?;
+                },
+                _ => return Err(CartLoadError::from("Ram save file has wrong size.")),
             }
             ram = ram_buffer;
         } else {
             ram = vec![0;ram_size];
         }
 
-        Ok(Cart {
+        let mut cart = Cart {
             rom: buffer,
             ram,
 
@@ -225,13 +411,25 @@ impl Cart {
             ram_enable: false,
             rom_bank: 1,
             ram_bank: 0,
+            rom_bank0: 0,
+            bank_low: 1,
+            bank_high: 0,
+
+            rtc,
 
             ram_size,
             ram_data_mask,
             ram_addr_mask,
+            multicart,
+
+            type_str,
 
-            type_str: decoded_type.5,
-        })
+            error: None,
+        };
+        // Carts without a mapper have their RAM always enabled
+        cart.ram_enable = matches!(cart.mapper_type, Type::ROM);
+        cart.mask_banks();
+        Ok(cart)
     }
 
 }
@@ -260,3 +458,221 @@ impl From<&'static str> for CartLoadError {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rtc::CLOCK_RATE;
+
+    // Cart of `banks` ROM banks starting with their bank number
+    fn synthetic_cart(cart_type: u8, banks: usize, ram_size: u8) -> Cart {
+        let mut rom = vec![0; banks*0x4000];
+        for bank in 0..banks {
+            rom[bank*0x4000] = bank as u8;
+        }
+        rom[0x147] = cart_type;
+        rom[0x149] = ram_size;
+        Cart::init(rom, None).unwrap()
+    }
+
+    #[test]
+    fn mbc1() {
+        let mut cart = synthetic_cart(0x01, 64, 0);
+
+        cart.write(0x2000, 0x13);
+        cart.write(0x4000, 0x01);
+        assert_eq!(cart.read(0x4000), 0x33);
+        assert_eq!(cart.read(0x0000), 0x00);
+        cart.write(0x2000, 0x20);
+        assert_eq!(cart.read(0x4000), 0x21);
+
+        // Mode 1 maps the upper bank register at 0x0000 too
+        cart.write(0x6000, 0x01);
+        assert_eq!(cart.read(0x0000), 0x20);
+        assert_eq!(cart.read(0x4000), 0x21);
+        cart.write(0x6000, 0x00);
+        assert_eq!(cart.read(0x0000), 0x00);
+    }
+
+    // Same checks as the mooneye emulator-only/mbc1 rom_* tests
+    #[test]
+    fn mbc1_rom_sizes() {
+        for banks in [32, 64, 128] {
+            let mut cart = synthetic_cart(0x01, banks, 0);
+            for mode in 0..2 {
+                cart.write(0x6000, mode);
+                for high in 0..4 {
+                    cart.write(0x4000, high);
+                    for low in 0..0x20 {
+                        cart.write(0x2000, low);
+                        let bank = (high as usize) << 5 | (low as usize).max(1);
+                        assert_eq!(cart.read(0x4000) as usize, bank%banks, "{} banks, {:x}:{:x}", banks, high, low);
+                        let bank0 = if mode == 1 { ((high as usize) << 5)%banks } else { 0 };
+                        assert_eq!(cart.read(0x0000) as usize, bank0, "{} banks, mode {}", banks, mode);
+                    }
+                }
+            }
+        }
+    }
+
+    // Same checks as the mooneye emulator-only/mbc1 ram_64kb and ram_256kb tests
+    #[test]
+    fn mbc1_ram_sizes() {
+        let mut cart = synthetic_cart(0x03, 32, 3);
+        assert_eq!(cart.read(0xa000), 0xff);
+        cart.write(0x0000, 0x0a);
+        cart.write(0x6000, 0x01);
+        for bank in 0..4 {
+            cart.write(0x4000, bank);
+            cart.write(0xbfff, 0x10 + bank);
+        }
+        cart.write(0x4000, 0x02);
+        assert_eq!(cart.read(0xbfff), 0x12);
+        // Mode 0 always maps RAM bank 0
+        cart.write(0x6000, 0x00);
+        assert_eq!(cart.read(0xbfff), 0x10);
+
+        // A single 8KiB bank is mapped whatever the bank register
+        let mut cart = synthetic_cart(0x03, 32, 2);
+        cart.write(0x0000, 0x0a);
+        cart.write(0x6000, 0x01);
+        cart.write(0xa000, 0x42);
+        cart.write(0x4000, 0x03);
+        assert_eq!(cart.read(0xa000), 0x42);
+        assert_eq!(cart.ram_offset(0xa000), Some(0));
+    }
+
+    #[test]
+    fn mbc5_large_ram() {
+        let mut cart = synthetic_cart(0x1A, 512, 4);
+        assert_eq!(cart.ram.len(), 128*1024);
+        cart.write(0x0000, 0x0a);
+        cart.write(0x4000, 0x0f);
+        cart.write(0xa000, 0x0f);
+        cart.write(0x3000, 0x01);
+        cart.write(0x2000, 0x23);
+        assert_eq!(cart.rom_bank(), 0x123);
+        assert_eq!(cart.ram_offset(0xa000), Some(15*0x2000));
+
+        // Banks past the ROM size wrap around
+        let mut cart = synthetic_cart(0x19, 8, 0);
+        cart.write(0x2000, 0x0b);
+        assert_eq!(cart.read(0x4000), 3);
+    }
+
+    #[test]
+    fn mbc1_multicart() {
+        let mut rom = synthetic_cart(0x01, 64, 0).rom;
+        for game in 0..4 {
+            rom[game*0x40000 + 0x104..game*0x40000 + 0x134].copy_from_slice(&LOGO);
+        }
+        let mut cart = Cart::init(rom, None).unwrap();
+        assert!(cart.multicart);
+
+        cart.write(0x2000, 0x13);
+        cart.write(0x4000, 0x02);
+        assert_eq!(cart.read(0x4000), 0x23);
+        cart.write(0x2000, 0x10);
+        assert_eq!(cart.read(0x4000), 0x20);
+        cart.write(0x6000, 0x01);
+        assert_eq!(cart.read(0x0000), 0x20);
+    }
+
+    #[test]
+    fn mbc2() {
+        let mut cart = synthetic_cart(0x06, 16, 0);
+
+        cart.write(0x2100, 0x03);
+        assert_eq!(cart.read(0x4000), 3);
+        cart.write(0x0100, 0x15);
+        assert_eq!(cart.read(0x4000), 5);
+        cart.write(0x3fff, 0x00);
+        assert_eq!(cart.read(0x4000), 1);
+        // Bit 8 clear selects the RAM enable, not the bank
+        cart.write(0x2000, 0x0a);
+        assert_eq!(cart.read(0x4000), 1);
+        cart.write(0x4100, 0x02);
+        assert_eq!(cart.read(0x4000), 1);
+
+        // 512 half-bytes echoed over the whole RAM area
+        cart.write(0xa005, 0x3c);
+        assert_eq!(cart.read(0xa005), 0xfc);
+        assert_eq!(cart.read(0xbe05), 0xfc);
+        cart.write(0xb3ff, 0x07);
+        assert_eq!(cart.read(0xa1ff), 0xf7);
+
+        // Disabled RAM ignores writes and reads as an open bus, in the whole
+        // echoed area
+        cart.write(0x0000, 0x00);
+        cart.write(0xa005, 0x01);
+        assert_eq!(cart.read(0xa005), 0xff);
+        assert_eq!(cart.read(0xbe05), 0xff);
+        assert_eq!(cart.read(0xa1ff), 0xff);
+        cart.write(0x0000, 0x0a);
+        assert_eq!(cart.read(0xa005), 0xfc);
+        assert_eq!(cart.read(0xa1ff), 0xf7);
+
+        // Any address with bit 8 clear up to 0x3fff writes the RAM enable
+        cart.write(0x3eff, 0x00);
+        assert_eq!(cart.read(0xa005), 0xff);
+        assert_eq!(cart.read(0x4000), 1);
+        cart.write(0x20ff, 0x0a);
+        assert_eq!(cart.read(0xa005), 0xfc);
+        assert_eq!(cart.ram.len(), 512);
+    }
+
+    #[test]
+    fn mbc3() {
+        let mut cart = synthetic_cart(0x10, 128, 3);
+
+        cart.write(0x2000, 0x00);
+        assert_eq!(cart.read(0x4000), 1);
+        cart.write(0x3fff, 0xc5);
+        assert_eq!(cart.read(0x4000), 0x45);
+
+        // RAM banks
+        cart.write(0x0000, 0x0a);
+        for bank in 0..4 {
+            cart.write(0x4000, bank);
+            cart.write(0xa123, 0x10 + bank);
+        }
+        cart.write(0x4000, 0x02);
+        assert_eq!(cart.read(0xa123), 0x12);
+
+        // Clock, read through the latch
+        cart.write(0x4000, 0x08);
+        cart.write(0xa000, 30);
+        cart.step(90*CLOCK_RATE + 1);
+        assert_eq!(cart.read(0xa000), 0xc0);
+        cart.write(0x6000, 0x00);
+        cart.write(0x6000, 0x01);
+        assert_eq!(cart.read(0xa000), 0xc0);
+        cart.write(0x4000, 0x09);
+        assert_eq!(cart.read(0xa000), 0xc0 | 2);
+
+        // Disabled registers ignore writes
+        cart.write(0x0000, 0x00);
+        cart.write(0xa000, 10);
+        assert_eq!(cart.rtc().unwrap().time(), (0, 0, 2, 0));
+    }
+
+    #[test]
+    fn save_data() {
+        let mut cart = synthetic_cart(0x10, 4, 2);
+        cart.write(0x0000, 0x0a);
+        cart.write(0xa000, 0x42);
+        cart.write(0x4000, 0x0a);
+        cart.write(0xa000, 7);
+
+        let data = cart.save_data();
+        assert_eq!(data.len(), 0x2000 + rtc::FOOTER_LENGTH);
+        let loaded = Cart::init(cart.rom.clone(), Some(data.clone())).unwrap();
+        assert_eq!(loaded.ram[0], 0x42);
+        assert_eq!(loaded.rtc().unwrap().time().1, 7);
+
+        // Footer with a 32-bit timestamp, or without footer
+        assert!(Cart::init(cart.rom.clone(), Some(data[..data.len() - 4].to_vec())).is_ok());
+        assert!(Cart::init(cart.rom.clone(), Some(data[..0x2000].to_vec())).is_ok());
+        assert!(Cart::init(cart.rom.clone(), Some(data[..data.len() - 1].to_vec())).is_err());
+    }
+}
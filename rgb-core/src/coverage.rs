@@ -0,0 +1,137 @@
+// Cart code coverage
+//
+// Records how each byte of the cart ROM and RAM was accessed during a session,
+// keyed by its offset in the cart (bank*bank_size + offset in the bank), as
+// one byte of flags per cart byte:
+//
+//   EXEC:  fetched by the CPU as part of an executed instruction
+//   READ:  read as data, by the CPU or by OAM DMA
+//   WRITE: written (cart RAM only, ROM writes control the mapper)
+//
+// The flag maps can be written as is to a file, and summarised per bank. The
+// accesses are recorded by `Mem`, instruction fetches are told apart from
+// data reads by `Dmg::step()`, which decodes the length of the instruction
+// about to run.
+
+use std::fmt::Write;
+
+use crate::cart::Cart;
+
+pub const EXEC: u8 = 0x01;
+pub const READ: u8 = 0x02;
+pub const WRITE: u8 = 0x04;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+pub struct Coverage {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    // Instruction about to be executed, as (address, length)
+    fetch: (u16, u16),
+}
+
+/// Accessed bytes of a bank
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BankSummary {
+    pub bank: usize,
+    pub size: usize,
+    pub executed: usize,
+    /// Read as data and never executed
+    pub read: usize,
+    pub written: usize,
+    pub unused: usize,
+}
+
+impl Coverage {
+    pub fn new(cart: &Cart) -> Coverage {
+        Coverage {
+            rom: vec![0; cart.rom.len()],
+            ram: vec![0; cart.ram.len()],
+            fetch: (0, 0),
+        }
+    }
+
+    /// Flags of each ROM byte
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    /// Flags of each cart RAM byte
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    pub fn rom_banks(&self) -> Vec<BankSummary> {
+        summarize(&self.rom, ROM_BANK_SIZE)
+    }
+
+    pub fn ram_banks(&self) -> Vec<BankSummary> {
+        summarize(&self.ram, RAM_BANK_SIZE)
+    }
+
+    /// Text summary of the ROM and RAM banks
+    pub fn summary(&self) -> String {
+        let mut summary = String::new();
+        let percent = |count: usize, size: usize| count as f64*100.0/size as f64;
+
+        writeln!(summary, "ROM bank {:>16} {:>16} {:>16}", "executed", "read", "unused").unwrap();
+        for bank in self.rom_banks() {
+            writeln!(summary, "{:>8} {:>7} ({:>5.1}%) {:>7} ({:>5.1}%) {:>7} ({:>5.1}%)", bank.bank,
+                     bank.executed, percent(bank.executed, bank.size), bank.read, percent(bank.read, bank.size),
+                     bank.unused, percent(bank.unused, bank.size)).unwrap();
+        }
+
+        let ram_banks = self.ram_banks();
+        if !ram_banks.is_empty() {
+            writeln!(summary, "\nRAM bank {:>16} {:>16} {:>16}", "read", "written", "unused").unwrap();
+            for bank in ram_banks {
+                writeln!(summary, "{:>8} {:>7} ({:>5.1}%) {:>7} ({:>5.1}%) {:>7} ({:>5.1}%)", bank.bank,
+                         bank.read, percent(bank.read, bank.size), bank.written, percent(bank.written, bank.size),
+                         bank.unused, percent(bank.unused, bank.size)).unwrap();
+            }
+        }
+
+        summary
+    }
+
+    pub(crate) fn set_fetch(&mut self, address: u16, length: u16) {
+        self.fetch = (address, length);
+    }
+
+    /// Record a CPU read, part of the instruction being executed or data
+    pub(crate) fn record_read(&mut self, cart: &Cart, address: u16) {
+        let (start, length) = self.fetch;
+        let flag = if address.wrapping_sub(start) < length { EXEC } else { READ };
+        self.record(cart, address, flag);
+    }
+
+    /// Record a write, only cart RAM writes are recorded
+    pub(crate) fn record_write(&mut self, cart: &Cart, address: u16) {
+        if let Some(offset) = cart.ram_offset(address) {
+            self.ram[offset] |= WRITE;
+        }
+    }
+
+    pub(crate) fn record(&mut self, cart: &Cart, address: u16, flag: u8) {
+        if let Some(offset) = cart.rom_offset(address) {
+            self.rom[offset] |= flag;
+        } else if let Some(offset) = cart.ram_offset(address) {
+            self.ram[offset] |= flag;
+        }
+    }
+}
+
+fn summarize(flags: &[u8], bank_size: usize) -> Vec<BankSummary> {
+    flags.chunks(bank_size).enumerate().map(|(bank, flags)| {
+        let count = |matches: fn(u8) -> bool| flags.iter().filter(|&&flag| matches(flag)).count();
+        BankSummary {
+            bank,
+            size: flags.len(),
+            executed: count(|flag| flag&EXEC != 0),
+            read: count(|flag| flag&(EXEC | READ) == READ),
+            written: count(|flag| flag&WRITE != 0),
+            unused: count(|flag| flag == 0),
+        }
+    }).collect()
+}
@@ -4,9 +4,16 @@
 #![allow(unused_variables)]
 
 use crate::bootstrap::Bootstrap;
+use crate::bus::Bus;
+use crate::callstack::{CallStack, Frame, FrameKind};
 use crate::cart::Cart;
+use crate::error::EmulationError;
 use crate::mem::Mem;
+use crate::savestate::{StateLoadError, StateReader, StateWriter};
+use crate::profile::{Activity, Profiler, StepStart};
+use crate::trace::Tracer;
 
+use std::collections::VecDeque;
 use std::fmt;
 
 #[cfg(feature="trace_cpu")]
@@ -52,7 +59,7 @@ const L_REGID: u8 = 5;
 const IND_HL_REGID: u8 = 6;
 const A_REGID: u8 = 7;
 
-const REG_NAMES: &[ &str ] = &["B", "C", "D", "E", "H", "L", "(HL)", "A"];
+pub(crate) const REG_NAMES: &[ &str ] = &["B", "C", "D", "E", "H", "L", "(HL)", "A"];
 
 // 16Bit register id as encoded in instructions
 const BC_REGID: u8 = 0;
@@ -60,7 +67,8 @@ const DE_REGID: u8 = 1;
 const HL_REGID: u8 = 2;
 const SP_REGID: u8 = 3;
 
-const DD_NAMES: &[ &str ] = &["BC", "DE", "HL", "SP"];
+pub(crate) const DD_NAMES: &[ &str ] = &["BC", "DE", "HL", "SP"];
+pub(crate) const QQ_NAMES: &[ &str ] = &["BC", "DE", "HL", "AF"];
 
 // ALU operations
 const ALU_ADD: u8 = 0;
@@ -72,7 +80,7 @@ const ALU_XOR: u8 = 5;
 const ALU_OR : u8 = 6;
 const ALU_CP : u8 = 7;
 
-const ALU_NAMES: &[ &str ] = &["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"];
+pub(crate) const ALU_NAMES: &[ &str ] = &["ADD", "ADC", "SUB", "SBC", "AND", "XOR", "OR", "CP"];
 
 // Jump conditions
 const COND_NZ: u8 = 0;
@@ -80,7 +88,7 @@ const COND_Z : u8 = 1;
 const COND_NC: u8 = 2;
 const COND_C : u8 = 3;
 
-const COND_NAMES: & [ &str ] = &["NZ", "Z", "NC", "C"];
+pub(crate) const COND_NAMES: & [ &str ] = &["NZ", "Z", "NC", "C"];
 
 // BC ALU operations
 const BCALU_RLC :u8 = 0;
@@ -92,21 +100,21 @@ const BCALU_SRA :u8 = 5;
 const BCALU_SWAP:u8 = 6;
 const BCALU_SRL :u8 = 7;
 
-const BCALU_NAMES: &[ &str ] = &["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+pub(crate) const BCALU_NAMES: &[ &str ] = &["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
 
 #[derive(PartialEq)]
-struct Regs {
-    a: u8,
-    b: u8,
-    c: u8,
-    d: u8,
-    e: u8,
-    f: u8,
-    h: u8,
-    l: u8,
-
-    pc: u16,
-    sp: u16,
+pub struct Regs {
+    pub a: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub f: u8,
+    pub h: u8,
+    pub l: u8,
+
+    pub pc: u16,
+    pub sp: u16,
 }
 
 impl fmt::Debug for Regs {
@@ -127,18 +135,107 @@ impl Regs {
 
 }
 
-pub struct Cpu {
+/// Opcode handler, called with the opcode
+type Handler<B> = fn(&mut Cpu<B>, u8) -> usize;
+
+/// How opcodes are dispatched to their handler, to compare the handler tables
+/// with decoding the opcode at every execution
+#[cfg(any(test, feature="bench"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dispatch {
+    /// Decode the opcode with `opcode_handler` when it is executed
+    Match,
+    /// Use the handler tables built from `opcode_handler`
+    Table,
+}
+
+/// LR35902 CPU, accessing memory through a `Bus`
+///
+/// By default the bus is the Gameboy memory map `Mem`.
+pub struct Cpu<B: Bus = Mem> {
     regs: Regs,
-    pub mem: Mem,
+    pub mem: B,
     pub cycle: usize,
     halted: bool,
     stoped: bool,
+    /// HALT was executed with IME=0 and an interrupt pending, the next
+    /// opcode is read without incrementing PC
+    halt_bug: bool,
+    /// An illegal opcode was executed, the CPU does nothing until reset
+    locked: bool,
+    /// Error raised by the last step, taken by the emulator
+    error: Option<EmulationError>,
+    /// Clock cycles of the current step already ticked on the bus
+    ticked: usize,
+    /// Interrupt master enable (IME)
     interrupts_enabled: bool,
+    /// EI was executed, IME is set after the next instruction
     interrupts_enabled_next: bool,
+    /// Logs each instruction before it is executed
+    tracer: Option<Tracer>,
+    /// Accounts the cycles of each step
+    profiler: Option<Profiler>,
+    /// Shadow call stack, for backtraces
+    call_stack: CallStack,
+    /// ROM bank mapped at 0x4000-0x7fff, set by the emulator before each step
+    /// for the tracer, profiler and call stack
+    rom_bank: usize,
+    #[cfg(any(test, feature="bench"))]
+    dispatch: Dispatch,
 }
 
 impl Cpu {
     pub fn new(bootstrap: Bootstrap, cart: Cart) -> Cpu {
+        Cpu::with_bus(Mem::new(bootstrap, cart))
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        for reg in [self.regs.a, self.regs.b, self.regs.c, self.regs.d,
+                    self.regs.e, self.regs.f, self.regs.h, self.regs.l] {
+            state.write_u8(reg);
+        }
+        state.write_u16(self.regs.pc);
+        state.write_u16(self.regs.sp);
+
+        state.write_usize(self.cycle);
+        state.write_bool(self.halted);
+        state.write_bool(self.stoped);
+        state.write_bool(self.halt_bug);
+        state.write_bool(self.locked);
+        state.write_bool(self.interrupts_enabled);
+        state.write_bool(self.interrupts_enabled_next);
+
+        self.mem.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        for reg in [&mut self.regs.a, &mut self.regs.b, &mut self.regs.c, &mut self.regs.d,
+                    &mut self.regs.e, &mut self.regs.f, &mut self.regs.h, &mut self.regs.l] {
+            *reg = state.read_u8()?;
+        }
+        self.regs.pc = state.read_u16()?;
+        self.regs.sp = state.read_u16()?;
+
+        self.cycle = state.read_usize()?;
+        self.halted = state.read_bool()?;
+        self.stoped = state.read_bool()?;
+        self.halt_bug = state.read_bool()?;
+        self.locked = state.read_bool()?;
+        self.interrupts_enabled = state.read_bool()?;
+        self.interrupts_enabled_next = state.read_bool()?;
+        self.call_stack.clear();
+
+        self.mem.load_state(state)
+    }
+}
+
+impl<B: Bus> Cpu<B> {
+    // Dispatch tables of the opcodes and of the CB prefixed opcodes
+    const OPCODES: [Handler<B>; 256] = Self::opcode_table();
+    const CB_OPCODES: [Handler<B>; 256] = Self::cb_opcode_table();
+
+    /// Create a CPU attached to a custom memory bus
+    pub fn with_bus(mem: B) -> Cpu<B> {
         Cpu {
             regs: Regs {
                 a: 0,
@@ -153,12 +250,22 @@ impl Cpu {
                 pc: 0,
                 sp: 0,
             },
-            mem: Mem::new(bootstrap, cart),
+            mem,
             cycle: 0,
             halted: false,
             stoped: false,
+            halt_bug: false,
+            locked: false,
+            error: None,
+            ticked: 0,
             interrupts_enabled_next: false,
             interrupts_enabled: false,
+            tracer: None,
+            profiler: None,
+            call_stack: CallStack::new(),
+            rom_bank: 0,
+            #[cfg(any(test, feature="bench"))]
+            dispatch: Dispatch::Table,
         }
     }
 
@@ -168,72 +275,217 @@ impl Cpu {
 
     pub fn get_pc(&self) -> u16 { self.regs.pc }
 
+    pub fn regs(&self) -> &Regs { &self.regs }
+
+    pub fn regs_mut(&mut self) -> &mut Regs { &mut self.regs }
+
+    /// Interrupt master enable flag
+    pub fn ime(&self) -> bool { self.interrupts_enabled }
+
+    pub fn set_ime(&mut self, enabled: bool) {
+        self.interrupts_enabled = enabled;
+        self.interrupts_enabled_next = false;
+    }
+
     pub fn execute_until(&mut self, cycle: usize) {
         while self.cycle < cycle {
+            let start = self.cycle;
             self.cycle += self.decode();
+            self.finish_step(start);
             trace!("{:?}", self.regs);
         }
     }
 
     pub fn step(&mut self) {
-        if (self.mem.reg_ie & self.mem.reg_if) != 0 {
+        let start = self.cycle;
+        let pending = self.mem.pending_interrupts();
+
+        // Only a joypad input leaves STOP mode
+        if self.stoped && self.mem.peek(0xff00)&0x0f != 0x0f {
             self.stoped = false;
-            self.halted = false;
         }
 
-        if self.interrupts_enabled && (self.mem.reg_ie & self.mem.reg_if) != 0 {
-            let int = self.mem.reg_ie&self.mem.reg_if;
-
-            if int&IRQ_VBLANK != 0 {
-                self.interrupt(0x40);
-                self.mem.reg_if &= !IRQ_VBLANK;
-            }
-            if int&IRQ_LCDSTAT != 0 {
-                self.interrupt(0x48);
-                self.mem.reg_if &= !IRQ_LCDSTAT;
-            }
-            if int&IRQ_TIMER != 0 {
-                self.interrupt(0x50);
-                self.mem.reg_if &= !IRQ_TIMER;
-            }
-            if int&IRQ_SERIAL != 0 {
-                self.interrupt(0x58);
-                self.mem.reg_if &= !IRQ_SERIAL;
-            }
-            if int&IRQ_JOYPAD != 0 {
-                self.interrupt(0x60);
-                self.mem.reg_if &= !IRQ_JOYPAD;
+        if pending != 0 && self.halted {
+            // Leaving HALT takes an extra cycle when the interrupt is serviced,
+            // with IME=0 the CPU simply resumes after the HALT instruction
+            self.halted = false;
+            if self.interrupts_enabled {
+                self.tick();
+                self.cycle += 4;
             }
+        }
 
-            self.cycle += 5*4;
-        } else if !self.stoped && !self.halted {
+        let profiled = self.profiler.is_some().then(|| StepStart::new(&self.regs, self.rom_bank, &self.call_stack));
+
+        let activity = if self.locked || self.stoped {
+            trace!("{}", if self.locked {"Locked!"} else {"Stopped!"});
+            self.cycle += 4;
+            Activity::Idle
+        } else if self.interrupts_enabled && pending != 0 {
+            self.cycle += self.interrupt();
+            Activity::Interrupt
+        } else if !self.halted {
             self.cycle += self.decode();
+            Activity::Instruction
         } else {
-            trace!("{}", if self.stoped {"Stopped!"} else {"Halted!"});
+            trace!("Halted!");
             self.cycle += 4;
-        }
+            Activity::Idle
+        };
 
+        self.finish_step(start);
         trace!("{:?}", self.regs);
+        self.call_stack.unwind(self.regs.sp);
+
+        if let (Some(profiler), Some(step_start)) = (&mut self.profiler, profiled) {
+            profiler.record(step_start, activity, self.cycle - start, &self.call_stack);
+        }
+    }
+
+    #[cfg(any(test, feature="bench"))]
+    pub fn set_dispatch(&mut self, dispatch: Dispatch) {
+        self.dispatch = dispatch;
+    }
+
+    pub(crate) fn set_rom_bank(&mut self, rom_bank: usize) {
+        self.rom_bank = rom_bank;
     }
 
     pub fn reset(&mut self) {
         self.regs.pc = 0;
+        self.locked = false;
+        self.call_stack.clear();
+    }
+
+    /// Shadow call stack, from the outermost to the innermost call
+    pub fn backtrace(&self) -> &VecDeque<Frame> { self.call_stack.frames() }
+
+    pub fn call_stack(&self) -> &CallStack { &self.call_stack }
+
+    pub fn print_backtrace(&self) {
+        print!("{}", self.call_stack.format(self.regs.pc, self.rom_bank, None));
+    }
+
+    /// The CPU executed an illegal opcode and is locked until reset
+    pub fn is_locked(&self) -> bool { self.locked }
+
+    /// Take the error raised by the last step, if any
+    pub fn take_error(&mut self) -> Option<EmulationError> {
+        self.error.take()
     }
 
     pub fn get_cycle(&self) -> usize { self.cycle }
 
+    /// Set or remove the instruction tracer, returns the previous one
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        std::mem::replace(&mut self.tracer, tracer)
+    }
+
+
+    /// Set or remove the execution profiler, returns the previous one
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        std::mem::replace(&mut self.profiler, profiler)
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> { self.profiler.as_ref() }
+
     pub fn print_regs(&self) { println!("{:?}", self.regs); }
 
     // Pivate methods
 
-    fn interrupt(&mut self, address: u8) {
-        trace!("{:04x}: INTERRUPT ${:02x}", self.regs.pc, address);
+    // Memory accesses tick the bus one M-cycle and happen at its end, the
+    // cycles of an instruction not spent accessing memory are ticked when it
+    // completes unless ticked explicitly with `tick()` before an access.
 
-        self.mem.write(self.regs.sp-1, (self.regs.pc>>8) as u8);
-        self.mem.write(self.regs.sp-2, self.regs.pc as u8);
-        self.regs.sp -= 2;
+    fn tick(&mut self) {
+        self.mem.tick(4);
+        self.ticked += 4;
+    }
 
-        self.regs.pc = address as u16;
+    fn read(&mut self, address: u16) -> u8 {
+        self.tick();
+        self.mem.read(address)
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.tick();
+        self.mem.write(address, data);
+    }
+
+    // Read the 16 bit immediate operand of the current instruction
+    fn read_imm16(&mut self) -> u16 {
+        let low = self.read(self.regs.pc.wrapping_add(1));
+        let high = self.read(self.regs.pc.wrapping_add(2));
+        (high as u16)<<8 | low as u16
+    }
+
+    fn push16(&mut self, value: u16) {
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, (value>>8) as u8);
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, value as u8);
+    }
+
+    fn pop16(&mut self) -> u16 {
+        let low = self.read(self.regs.sp);
+        let high = self.read(self.regs.sp.wrapping_add(1));
+        self.regs.sp = self.regs.sp.wrapping_add(2);
+        (high as u16)<<8 | low as u16
+    }
+
+    // Tick the cycles of the step not ticked yet by memory accesses
+    fn finish_step(&mut self, start: usize) {
+        let elapsed = self.cycle - start;
+        if elapsed > self.ticked {
+            self.mem.tick(elapsed - self.ticked);
+        }
+        self.ticked = 0;
+    }
+
+    // Record a call to the current PC, after the return address was pushed
+    fn record_call(&mut self, kind: FrameKind, call_site: u16, return_address: u16) {
+        self.call_stack.call(Frame {
+            kind,
+            call_site,
+            target: self.regs.pc,
+            return_address,
+            sp: self.regs.sp,
+            rom_bank: self.rom_bank,
+        });
+    }
+
+    // Service the highest priority pending interrupt, in 5 M-cycles
+    fn interrupt(&mut self) -> usize {
+        self.interrupts_enabled = false;
+        self.interrupts_enabled_next = false;
+
+        self.tick();
+        self.tick();
+
+        let pc = self.regs.pc;
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, (pc>>8) as u8);
+
+        // The interrupt is chosen after the high byte of PC is pushed, if that
+        // write cleared it in IE the dispatch is cancelled and jumps to $0000
+        let pending = self.mem.pending_interrupts();
+
+        self.regs.sp = self.regs.sp.wrapping_sub(1);
+        self.write(self.regs.sp, pc as u8);
+
+        if pending == 0 {
+            trace!("{:04x}: INTERRUPT cancelled", pc);
+            self.regs.pc = 0;
+        } else {
+            // The lowest bit has the highest priority
+            let interrupt = pending & pending.wrapping_neg();
+            self.mem.acknowledge_interrupt(interrupt);
+            self.regs.pc = 0x40 + 8*interrupt.trailing_zeros() as u16;
+            trace!("{:04x}: INTERRUPT ${:02x}", pc, self.regs.pc);
+        }
+        self.record_call(FrameKind::Interrupt, pc, pc);
+
+        20
     }
 
     fn set_flag(&mut self, flag: u8, value: bool) {
@@ -254,13 +506,13 @@ impl Cpu {
             E_REGID => self.regs.e = value,
             H_REGID => self.regs.h = value,
             L_REGID => self.regs.l = value,
-            IND_HL_REGID => self.mem.write((self.regs.h as u16)<<8 | self.regs.l as u16, value),
+            IND_HL_REGID => self.write((self.regs.h as u16)<<8 | self.regs.l as u16, value),
             A_REGID => self.regs.a = value,
             _ => panic!("Wrong reg id")
         }
     }
 
-    fn get_reg8_by_id(&self, id: u8) -> u8 {
+    fn get_reg8_by_id(&mut self, id: u8) -> u8 {
         match id {
             B_REGID => self.regs.b,
             C_REGID => self.regs.c,
@@ -268,7 +520,7 @@ impl Cpu {
             E_REGID => self.regs.e,
             H_REGID => self.regs.h,
             L_REGID => self.regs.l,
-            IND_HL_REGID => self.mem.read((self.regs.h as u16)<<8 | self.regs.l as u16),
+            IND_HL_REGID => self.read((self.regs.h as u16)<<8 | self.regs.l as u16),
             A_REGID => self.regs.a,
             _ => panic!("Wrong reg id")
         }
@@ -308,95 +560,150 @@ impl Cpu {
     }
 
     fn decode(&mut self) -> usize {
-        let interrupts_enabled_next = self.interrupts_enabled_next;
-
-        let instr = self.mem.read(self.regs.pc);
-        let cycle = match instr {
-            0x00 => self.nop(),
-            0xC3 => self.jp(true, false, 0),
-            0xE9 => self.jp(false, false, 0),
-            0xCB => self.decode_cb(),
-            0xFA => self.ld_a_ind_nn(),
-            0x08 => self.ld_ind_a16_sp(),
-            0x76 => self.halt(),
-            0x10 => self.stop(),
-            0x18 => self.jr(false, 0),
-            0xCD => self.call(false, 0),
-            0xC9 => self.ret(false, 0),
-            0xD9 => self.reti(),
-            0x27 => self.daa(),
-            0x2F => self.cpl(),
-            0x37 => self.scf(),
-            0x3f => self.ccf(),
-            0xE8 => self.add_sp_r8(),
-            0xF8 => self.ld_hl_sp_r8(),
-            0xF9 => self.ld_sp_hl(),
-            0xFE => self.cp_d8(),
-            _ if instr&0xE7 == 0x20 => self.jr(true, (instr>>3)&0x03),
-            _ if instr&0xE7 == 0xC2 => self.jp(true, true, (instr>>3)&0x03),
-            _ if instr&0xCF == 0x01 => self.ld_dd_nn((instr>>4)&0x03),
-            _ if instr&0xC7 == 0x02 => self.ld_ind(false, instr&0x08==0, (instr>>4)&0x03),
-            _ if instr&0xEF == 0xEA => self.ld_ind(true, instr&0x10==0, 0),
-            _ if instr&0xC7 == 0x06 => self.ld_r_n((instr>>3)&0x7),
-            _ if instr&0xC0 == 0x40 => self.ld_r_r((instr>>3)&0x7, instr&0x7),
-            _ if instr&0xC0 == 0x80 => self.alu(false, (instr>>3)&0x7, instr&0x7),
-            _ if instr&0xC7 == 0xC6 => self.alu(true, (instr>>3)&0x7, 0),
-            _ if instr&0xC7 == 0xC7 => self.rst(instr&0x38),
-            _ if instr&0xC7 == 0x03 => self.inc_dec_dd(instr&0x08==0, (instr>>4)&0x03),
-            _ if instr&0xC6 == 0x04 => self.inc_dec_r(instr&0x01==0, (instr>>3)&0x07),
-            _ if instr&0xED == 0xE0 => self.ldh(instr&0x02==0, instr&0x10==0),
-            _ if instr&0xC7 == 0xC4 => self.call(true, (instr>>3)&0x03),
-            _ if instr&0xE7 == 0xC0 => self.ret(true, (instr>>3)&0x03),
-            _ if instr&0xCF == 0xC1 => self.push_pop_qq(true, (instr>>4)&0x03),
-            _ if instr&0xCF == 0xC5 => self.push_pop_qq(false, (instr>>4)&0x03),
-            _ if instr&0xE7 == 0x07 => self.rotate((instr>>3)&0x03),
-            _ if instr&0xF7 == 0xF3 => self.dei(instr&0x08 != 0),
-            _ if instr&0xCF == 0x09 => self.add_hl_ss((instr&0x30)>>4),
-            _ => {
-                trace!("\n{:?}", self.regs);
-                panic!("Invalid instruction op: 0x{:02x} at addr 0x{:04x}!", instr, self.regs.pc)
-            }
-        };
-        self.interrupts_enabled = interrupts_enabled_next;
+        let enable_interrupts = self.interrupts_enabled_next;
+
+        if let Some(tracer) = &mut self.tracer {
+            tracer.trace(&self.regs, &self.mem, self.cycle, self.rom_bank);
+        }
+
+        let instr = self.read(self.regs.pc);
+        if self.halt_bug {
+            // The opcode is read again as the next byte of the instruction
+            self.halt_bug = false;
+            self.regs.pc = self.regs.pc.wrapping_sub(1);
+        }
+        let cycle = self.handler(instr)(self, instr);
+        // EI takes effect after the following instruction, unless it was a DI
+        if enable_interrupts && self.interrupts_enabled_next {
+            self.interrupts_enabled = true;
+            self.interrupts_enabled_next = false;
+        }
 
         cycle
     }
 
+    #[cfg(not(any(test, feature="bench")))]
+    fn handler(&self, opcode: u8) -> Handler<B> {
+        Self::OPCODES[opcode as usize]
+    }
+
+    #[cfg(any(test, feature="bench"))]
+    fn handler(&self, opcode: u8) -> Handler<B> {
+        match self.dispatch {
+            Dispatch::Match => Self::opcode_handler(opcode),
+            Dispatch::Table => Self::OPCODES[opcode as usize],
+        }
+    }
+
+    // Opcode handlers indexed by opcode
+    const fn opcode_table() -> [Handler<B>; 256] {
+        let mut table: [Handler<B>; 256] = [|cpu, opcode| cpu.illegal(opcode); 256];
+        let mut index = 0;
+        while index < 256 {
+            table[index] = Self::opcode_handler(index as u8);
+            index += 1;
+        }
+        table
+    }
+
+    // Decode an opcode to its handler
+    const fn opcode_handler(opcode: u8) -> Handler<B> {
+        match opcode {
+            0x00 => |cpu, _| cpu.nop(),
+            0xC3 => |cpu, _| cpu.jp(true, false, 0),
+            0xE9 => |cpu, _| cpu.jp(false, false, 0),
+            0xCB => |cpu, _| cpu.decode_cb(),
+            0xFA => |cpu, _| cpu.ld_a_ind_nn(),
+            0x08 => |cpu, _| cpu.ld_ind_a16_sp(),
+            0x76 => |cpu, _| cpu.halt(),
+            0x10 => |cpu, _| cpu.stop(),
+            0x18 => |cpu, _| cpu.jr(false, 0),
+            0xCD => |cpu, _| cpu.call(false, 0),
+            0xC9 => |cpu, _| cpu.ret(false, 0),
+            0xD9 => |cpu, _| cpu.reti(),
+            0x27 => |cpu, _| cpu.daa(),
+            0x2F => |cpu, _| cpu.cpl(),
+            0x37 => |cpu, _| cpu.scf(),
+            0x3f => |cpu, _| cpu.ccf(),
+            0xE8 => |cpu, _| cpu.add_sp_r8(),
+            0xF8 => |cpu, _| cpu.ld_hl_sp_r8(),
+            0xF9 => |cpu, _| cpu.ld_sp_hl(),
+            0xFE => |cpu, _| cpu.cp_d8(),
+            _ if opcode&0xE7 == 0x20 => |cpu, op| cpu.jr(true, (op>>3)&0x03),
+            _ if opcode&0xE7 == 0xC2 => |cpu, op| cpu.jp(true, true, (op>>3)&0x03),
+            _ if opcode&0xCF == 0x01 => |cpu, op| cpu.ld_dd_nn((op>>4)&0x03),
+            _ if opcode&0xC7 == 0x02 => |cpu, op| cpu.ld_ind(false, op&0x08==0, (op>>4)&0x03),
+            _ if opcode&0xEF == 0xEA => |cpu, op| cpu.ld_ind(true, op&0x10==0, 0),
+            _ if opcode&0xC7 == 0x06 => |cpu, op| cpu.ld_r_n((op>>3)&0x7),
+            _ if opcode&0xC0 == 0x40 => |cpu, op| cpu.ld_r_r((op>>3)&0x7, op&0x7),
+            _ if opcode&0xC0 == 0x80 => |cpu, op| cpu.alu(false, (op>>3)&0x7, op&0x7),
+            _ if opcode&0xC7 == 0xC6 => |cpu, op| cpu.alu(true, (op>>3)&0x7, 0),
+            _ if opcode&0xC7 == 0xC7 => |cpu, op| cpu.rst(op&0x38),
+            _ if opcode&0xC7 == 0x03 => |cpu, op| cpu.inc_dec_dd(op&0x08==0, (op>>4)&0x03),
+            _ if opcode&0xC6 == 0x04 => |cpu, op| cpu.inc_dec_r(op&0x01==0, (op>>3)&0x07),
+            _ if opcode&0xED == 0xE0 => |cpu, op| cpu.ldh(op&0x02==0, op&0x10==0),
+            _ if opcode&0xC7 == 0xC4 => |cpu, op| cpu.call(true, (op>>3)&0x03),
+            _ if opcode&0xE7 == 0xC0 => |cpu, op| cpu.ret(true, (op>>3)&0x03),
+            _ if opcode&0xCF == 0xC1 => |cpu, op| cpu.push_pop_qq(true, (op>>4)&0x03),
+            _ if opcode&0xCF == 0xC5 => |cpu, op| cpu.push_pop_qq(false, (op>>4)&0x03),
+            _ if opcode&0xE7 == 0x07 => |cpu, op| cpu.rotate((op>>3)&0x03),
+            _ if opcode&0xF7 == 0xF3 => |cpu, op| cpu.dei(op&0x08 != 0),
+            _ if opcode&0xCF == 0x09 => |cpu, op| cpu.add_hl_ss((op&0x30)>>4),
+            _ => |cpu, op| cpu.illegal(op),
+        }
+    }
+
+    fn illegal(&mut self, instr: u8) -> usize {
+        trace!("{:04x}: Illegal instruction op: 0x{:02x}", self.regs.pc, instr);
+        self.locked = true;
+        self.error = Some(EmulationError::IllegalOpcode { opcode: instr, address: self.regs.pc });
+        4
+    }
+
     fn nop(&mut self) -> usize {
         trace!("{:04x}: NOP", self.regs.pc);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
         4
     }
 
-    #[allow(unreachable_code)]
     fn halt(&mut self) -> usize {
         trace!("{:04x}: HALT", self.regs.pc);
-        self.halted = true;
-        self.regs.pc += 1;
-        //panic!("Halt not implemented!");
+        // With IME=0 and an interrupt already pending HALT exits immediately
+        // and PC fails to increment after the next opcode fetch
+        if !self.interrupts_enabled && self.mem.pending_interrupts() != 0 {
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
+        self.regs.pc = self.regs.pc.wrapping_add(1);
         4
     }
 
-    #[allow(unreachable_code)]
     fn stop(&mut self) -> usize {
         trace!("{:04x}: STOP", self.regs.pc);
-        //self.stoped = true;
-        self.regs.pc += 1;
-        //panic!("STOP not implemented!");
-        4
+        // STOP is followed by a padding byte and resets DIV, the reset is a
+        // bus access in the M-cycle after the opcode fetch
+        self.write(0xff04, 0);
+        self.stoped = true;
+        self.regs.pc = self.regs.pc.wrapping_add(2);
+        8
     }
 
     fn dei(&mut self, enable: bool) -> usize {
         trace!("{:04x}: {}", self.regs.pc, if enable {"EI"} else {"DI"});
+        // DI is immediate, EI is delayed by one instruction
+        if !enable {
+            self.interrupts_enabled = false;
+        }
         self.interrupts_enabled_next = enable;
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
         4
     }
 
     fn jp(&mut self, immediate: bool, conditional: bool, condition: u8) -> usize {
         let address;
         if immediate{
-            address = (self.mem.read(self.regs.pc+2) as u16)<<8 |  self.mem.read(self.regs.pc+1) as u16;
+            address = self.read_imm16();
             if TRACE_ENABLE {
                 let cond_str = if conditional { format!("{}, ", COND_NAMES[condition as usize]) } else { String::from("") };
                 trace!("{:04x}: JP {}${:04x}", self.regs.pc, cond_str, address);
@@ -411,19 +718,19 @@ impl Cpu {
 
             if immediate {16} else {4}
         } else {
-            self.regs.pc += 3;
+            self.regs.pc = self.regs.pc.wrapping_add(3);
             12
         }
     }
 
     fn jr(&mut self, conditional: bool, condition: u8) -> usize {
-        let val = self.mem.read(self.regs.pc+1) as i8;
+        let val = self.read(self.regs.pc.wrapping_add(1)) as i8;
         if TRACE_ENABLE {
             let cond_str = if conditional { format!("{}, ", COND_NAMES[condition as usize]) } else { String::from("") };
             trace!("{:04x}: JR {}{}", self.regs.pc, cond_str, val);
         }
         
-        self.regs.pc += 2;
+        self.regs.pc = self.regs.pc.wrapping_add(2);
         if !conditional || self.test_condition(condition) {
             let newpc = (self.regs.pc as i32) + (val as i32);
             self.regs.pc = newpc as u16;
@@ -435,27 +742,29 @@ impl Cpu {
 
     fn rst(&mut self, address: u8) -> usize {
         trace!("{:04x}: RST ${:02x}", self.regs.pc, address);
-        self.regs.pc += 1;
-        self.mem.write(self.regs.sp-1, (self.regs.pc>>8) as u8);
-        self.mem.write(self.regs.sp-2, self.regs.pc as u8);
-        self.regs.sp -= 2;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        self.tick();
+        self.push16(self.regs.pc);
+        let return_address = self.regs.pc;
         self.regs.pc = address as u16;
+        self.record_call(FrameKind::Rst, return_address.wrapping_sub(1), return_address);
 
         16
     }
 
     fn call(&mut self, conditional: bool, condition: u8) -> usize {
-        let newpc = (self.mem.read(self.regs.pc+2) as u16)<<8 |  self.mem.read(self.regs.pc+1) as u16;
+        let newpc = self.read_imm16();
         if TRACE_ENABLE {
             let cond_str = if conditional { format!("{}, ", COND_NAMES[condition as usize]) } else { String::from("") };
             trace!("{:04x}: CALL {}${:04x}", self.regs.pc, cond_str, newpc);
         }
-        self.regs.pc += 3;
+        self.regs.pc = self.regs.pc.wrapping_add(3);
         if !conditional || self.test_condition(condition) {
-            self.mem.write(self.regs.sp-1, (self.regs.pc>>8) as u8);
-            self.mem.write(self.regs.sp-2, self.regs.pc as u8);
-            self.regs.sp -= 2;
+            self.tick();
+            self.push16(self.regs.pc);
+            let return_address = self.regs.pc;
             self.regs.pc = newpc;
+            self.record_call(FrameKind::Call, return_address.wrapping_sub(3), return_address);
             24
         } else {
             12
@@ -467,12 +776,15 @@ impl Cpu {
             let cond_str = if conditional { format!(" {}", COND_NAMES[condition as usize]) } else { String::from("") };
             trace!("{:04x}: RET{}", self.regs.pc, cond_str);
         }
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        if conditional {
+            // Evaluating the condition takes one M-cycle
+            self.tick();
+        }
         if !conditional || self.test_condition(condition) {
-            let pc_l = self.mem.read(self.regs.sp);
-            let pc_h = self.mem.read(self.regs.sp+1);
-            self.regs.sp += 2;
-            self.regs.pc = (pc_h as u16)<<8 | pc_l as u16;
+            let sp = self.regs.sp;
+            self.regs.pc = self.pop16();
+            self.call_stack.ret(sp, self.regs.pc);
             if conditional { 20 } else { 16 }
         } else {
             8
@@ -482,13 +794,12 @@ impl Cpu {
     fn reti(&mut self) -> usize {
         trace!("{:04x}: RETI", self.regs.pc);
 
-        let pc_l = self.mem.read(self.regs.sp);
-        let pc_h = self.mem.read(self.regs.sp+1);
-        self.regs.sp += 2;
-        self.regs.pc = (pc_h as u16)<<8 | pc_l as u16;
+        let sp = self.regs.sp;
+        self.regs.pc = self.pop16();
+        self.call_stack.ret(sp, self.regs.pc);
 
         self.interrupts_enabled = true;
-        self.interrupts_enabled_next = true;
+        self.interrupts_enabled_next = false;
 
         16
     }
@@ -498,50 +809,42 @@ impl Cpu {
         let address;
 
         if immediate {
-            address = self.mem.read(self.regs.pc+1);
-            self.regs.pc += 2;
+            address = self.read(self.regs.pc.wrapping_add(1));
+            self.regs.pc = self.regs.pc.wrapping_add(2);
         } else {
             address = self.regs.c;
-            self.regs.pc += 1;
+            self.regs.pc = self.regs.pc.wrapping_add(1);
         }
 
         #[cfg(feature="trace_cpu")]
-        {
-            let addr_str;
-            
-            if immediate {
-                addr_str = format!("${:02x}", address);
-            } else {
-                addr_str = "C".to_owned();
-            }
-        }
+        let addr_str = if immediate { format!("${:02x}", address) } else { "C".to_owned() };
 
         if store {
             trace!("{:04x}: LD ($FF00+{}), A", self.regs.pc, addr_str);
-            self.mem.write(0xff00 + (address as u16), self.regs.a);
+            self.write(0xff00 + (address as u16), self.regs.a);
         } else {
             trace!("{:04x}: LD A, ($FF00+{})", self.regs.pc, addr_str);
-            self.regs.a = self.mem.read(0xff00 + (address as u16));
+            self.regs.a = self.read(0xff00 + (address as u16));
         }
 
         if immediate { 12 } else { 8 }
     }
 
     fn ld_ind_a16_sp(&mut self) -> usize {
-        let addr = (self.mem.read(self.regs.pc+2) as u16)<<8 |  self.mem.read(self.regs.pc+1) as u16;
+        let addr = self.read_imm16();
         trace!("{:04x}: LD (${:04x}), SP", self.regs.pc, addr);
-        self.regs.pc += 3;
+        self.regs.pc = self.regs.pc.wrapping_add(3);
 
-        self.mem.write(addr, (self.regs.sp&0xff) as u8);
-        self.mem.write(addr.wrapping_add(1), (self.regs.sp>>8) as u8);
+        self.write(addr, (self.regs.sp&0xff) as u8);
+        self.write(addr.wrapping_add(1), (self.regs.sp>>8) as u8);
 
         20
     }
 
     fn ld_dd_nn(&mut self, reg_id: u8) -> usize {
-        let value = (self.mem.read(self.regs.pc+2) as u16)<<8 |  self.mem.read(self.regs.pc+1) as u16;
+        let value = self.read_imm16();
         trace!("{:04x}: LD {}, ${:04x}", self.regs.pc, DD_NAMES[reg_id as usize], value);
-        self.regs.pc += 3;
+        self.regs.pc = self.regs.pc.wrapping_add(3);
 
         self.set_reg16_by_id(reg_id, value);
 
@@ -550,7 +853,7 @@ impl Cpu {
 
     fn ld_sp_hl(&mut self) -> usize {
         trace!("{:04x}: LD SP, HL", self.regs.pc);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         self.regs.sp = self.get_reg16_by_id(HL_REGID);
 
@@ -561,14 +864,14 @@ impl Cpu {
         let address: u16;
 
         let address= if immediate {
-            (self.mem.read(self.regs.pc+2) as u16)<<8 |  self.mem.read(self.regs.pc+1) as u16
+            self.read_imm16()
         } else {
             match reg_id {
                 0 => (self.regs.b as u16) << 8 | self.regs.c as u16,
                 1 => (self.regs.d as u16) << 8 | self.regs.e as u16,
                 2 => {
                     let hl = (self.regs.h as u16) << 8 | self.regs.l as u16;
-                    let new_hl = hl+1;
+                    let new_hl = hl.wrapping_add(1);
                     self.regs.h = (new_hl >> 8) as u8;
                     self.regs.l = new_hl as u8;
 
@@ -588,9 +891,9 @@ impl Cpu {
 
 
         if store {
-            self.mem.write(address, self.regs.a); 
+            self.write(address, self.regs.a);
         } else {
-            self.regs.a = self.mem.read(address);
+            self.regs.a = self.read(address);
         }
 
         #[cfg(feature="trace_cpu")]
@@ -615,48 +918,50 @@ impl Cpu {
         }
 
         if immediate {
-            self.regs.pc += 3;
+            self.regs.pc = self.regs.pc.wrapping_add(3);
             16
         } else {
-            self.regs.pc += 1;
+            self.regs.pc = self.regs.pc.wrapping_add(1);
             8
         }
     }
 
     fn push_pop_qq(&mut self, pop: bool, reg_id: u8) -> usize {
-        let (reg_h, reg_l, reg_name) = match reg_id {
-            0 => (&mut self.regs.b, &mut self.regs.c, "BC"),
-            1 => (&mut self.regs.d, &mut self.regs.e, "DE"),
-            2 => (&mut self.regs.h, &mut self.regs.l, "HL"),
-            3 => (&mut self.regs.a, &mut self.regs.f, "AF"),
-            _ => panic!("Bug in decoding")
-        };
+        let reg_name = QQ_NAMES[reg_id as usize];
 
         if pop {
-            if reg_id == 3 {
-                *reg_l = self.mem.read(self.regs.sp)&0xf0;
-            } else {
-                *reg_l = self.mem.read(self.regs.sp);
-            }
-            *reg_h = self.mem.read(self.regs.sp.wrapping_add(1));
-            self.regs.sp = self.regs.sp.wrapping_add(2);
+            let value = self.pop16();
+            let (reg_h, reg_l) = self.qq_regs(reg_id);
+            *reg_h = (value>>8) as u8;
+            *reg_l = if reg_id == 3 { value as u8&0xf0 } else { value as u8 };
             trace!("{:04x}: POP {}", self.regs.pc, reg_name);
         } else {
-            self.mem.write(self.regs.sp.wrapping_sub(1), *reg_h);
-            self.mem.write(self.regs.sp.wrapping_sub(2), *reg_l);
-            self.regs.sp = self.regs.sp.wrapping_sub(2);
+            let (reg_h, reg_l) = self.qq_regs(reg_id);
+            let value = (*reg_h as u16)<<8 | *reg_l as u16;
+            self.tick();
+            self.push16(value);
             trace!("{:04x}: PUSH {}", self.regs.pc, reg_name);
         }
 
 
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
         if pop { 12 } else { 16 }
     }
 
+    fn qq_regs(&mut self, reg_id: u8) -> (&mut u8, &mut u8) {
+        match reg_id {
+            0 => (&mut self.regs.b, &mut self.regs.c),
+            1 => (&mut self.regs.d, &mut self.regs.e),
+            2 => (&mut self.regs.h, &mut self.regs.l),
+            3 => (&mut self.regs.a, &mut self.regs.f),
+            _ => panic!("Bug in decoding")
+        }
+    }
+
     // Algorithm by AaronLiu (HFO4) translated from https://github.com/HFO4/gameboy.live/blob/657501f18a60c486366cd04b87025a7781db1fd1/gb/opcodes.go#L1351-L1377
     fn daa(&mut self) -> usize {
         trace!("{:04x}: DAA", self.regs.pc);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         if !self.get_flag(SUBSTRACT_FLAG) {
             if self.get_flag(CARRY_FLAG) || self.regs.a > 0x99 {
@@ -684,7 +989,7 @@ impl Cpu {
 
     fn rotate(&mut self, operation: u8) -> usize {
         trace!("{:04x}: {}A", self.regs.pc, BCALU_NAMES[operation as usize]);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         let mut value = self.regs.a;
         match operation {
@@ -730,14 +1035,14 @@ impl Cpu {
     }
 
     fn add_sp_r8(&mut self) -> usize {
-        let value = ((self.mem.read(self.regs.pc+1) as i8) as i16) as u16;
+        let value = ((self.read(self.regs.pc.wrapping_add(1)) as i8) as i16) as u16;
         trace!("{:04x}: ADD SP, {}", self.regs.pc, value);
-        self.regs.pc += 2;
+        self.regs.pc = self.regs.pc.wrapping_add(2);
 
         let result = self.regs.sp.wrapping_add(value);
         let hresult = (self.regs.sp&0xff) + (value&0xff);
         let hhresult = (self.regs.sp&0x0f) + (value&0x0f);
-        self.regs.sp = result as u16;
+        self.regs.sp = result;
 
         self.set_flag(ZERO_FLAG, false);
         self.set_flag(SUBSTRACT_FLAG, false);
@@ -748,15 +1053,15 @@ impl Cpu {
     }
 
     fn ld_hl_sp_r8(&mut self) -> usize {
-        let ivalue = (self.mem.read(self.regs.pc+1) as i8) as i16;
+        let ivalue = (self.read(self.regs.pc.wrapping_add(1)) as i8) as i16;
         let value = ivalue as u16;
         trace!("{:04x}: LD HL, SP{:+}", self.regs.pc, ivalue);
-        self.regs.pc += 2;
+        self.regs.pc = self.regs.pc.wrapping_add(2);
 
         let result = self.regs.sp.wrapping_add(value);
         let hresult = (self.regs.sp&0xff) + (value&0xff);
         let hhresult = (self.regs.sp&0x0f) + (value&0x0f);
-        self.set_reg16_by_id(HL_REGID, result as u16);
+        self.set_reg16_by_id(HL_REGID, result);
 
         self.set_flag(ZERO_FLAG, false);
         self.set_flag(SUBSTRACT_FLAG, false);
@@ -768,7 +1073,7 @@ impl Cpu {
 
     fn add_hl_ss(&mut self, reg_id: u8) -> usize {
         trace!("{:04x}: ADD HL, {}", self.regs.pc, DD_NAMES[reg_id as usize]);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         let hl = self.get_reg16_by_id(HL_REGID);
         let reg = self.get_reg16_by_id(reg_id);
@@ -784,9 +1089,9 @@ impl Cpu {
     }
 
     fn cp_d8(&mut self) -> usize {
-        let value = self.mem.read(self.regs.pc+1);
+        let value = self.read(self.regs.pc.wrapping_add(1));
         trace!("{:04x}: CP ${:02x}", self.regs.pc, value);
-        self.regs.pc += 2;
+        self.regs.pc = self.regs.pc.wrapping_add(2);
 
         let a = self.regs.a;
         self.set_flag(CARRY_FLAG, a < value);
@@ -799,7 +1104,7 @@ impl Cpu {
 
     fn cpl(&mut self) -> usize {
         trace!("{:04x}: CPL", self.regs.pc);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         self.regs.a = !self.regs.a;
         self.set_flag(SUBSTRACT_FLAG, true);
@@ -810,7 +1115,7 @@ impl Cpu {
 
     fn ccf(&mut self) -> usize {
         trace!("{:04x}: CPL", self.regs.pc);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         let cy = self.get_flag(CARRY_FLAG);
         self.set_flag(CARRY_FLAG, !cy);
@@ -822,7 +1127,7 @@ impl Cpu {
 
     fn scf(&mut self) -> usize {
         trace!("{:04x}: SCF", self.regs.pc);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         self.set_flag(CARRY_FLAG, true);
         self.set_flag(SUBSTRACT_FLAG, false);
@@ -834,13 +1139,13 @@ impl Cpu {
     fn alu(&mut self, immediate: bool, operation: u8, reg_id: u8) -> usize {
         let val: u16;
         if immediate {
-            val = self.mem.read(self.regs.pc+1) as u16;
+            val = self.read(self.regs.pc.wrapping_add(1)) as u16;
             trace!("{:04x}: {} ${:02x}", self.regs.pc, ALU_NAMES[operation as usize], val);
-            self.regs.pc += 2;
+            self.regs.pc = self.regs.pc.wrapping_add(2);
         } else {
             val = self.get_reg8_by_id(reg_id) as u16;
             trace!("{:04x}: {} {}", self.regs.pc, ALU_NAMES[operation as usize], REG_NAMES[reg_id as usize]);
-            self.regs.pc += 1;
+            self.regs.pc = self.regs.pc.wrapping_add(1);
         }
         let a = self.regs.a as u16;
         let result: u16;
@@ -914,7 +1219,7 @@ impl Cpu {
 
     fn inc_dec_dd(&mut self, inc:bool, reg_id: u8) -> usize {
         trace!("{:04x}: {} {}", self.regs.pc, if inc {"INC"} else {"DEC"}, DD_NAMES[reg_id as usize]);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         let result = self.get_reg16_by_id(reg_id);
         if inc {
@@ -928,7 +1233,7 @@ impl Cpu {
 
     fn inc_dec_r(&mut self, inc: bool, reg_id: u8) -> usize {
         trace!("{:04x}: {} {}", self.regs.pc, if inc {"INC"} else {"DEC"}, REG_NAMES[reg_id as usize]);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         let reg = self.get_reg8_by_id(reg_id) as i16;
         let result;
@@ -952,7 +1257,7 @@ impl Cpu {
 
     fn ld_r_r(&mut self, dest_reg:u8, src_reg:u8) -> usize {
         trace!("{:04x}: LD {}, {}", self.regs.pc, REG_NAMES[dest_reg as usize], REG_NAMES[src_reg as usize]);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         let value = self.get_reg8_by_id(src_reg);
         self.set_reg8_by_id(dest_reg, value);
@@ -961,9 +1266,9 @@ impl Cpu {
     }
 
     fn ld_r_n(&mut self, dest_reg: u8) -> usize {
-        let value = self.mem.read(self.regs.pc+1);
+        let value = self.read(self.regs.pc.wrapping_add(1));
         trace!("{:04x}: LD {}, ${:02x}", self.regs.pc, REG_NAMES[dest_reg as usize], value);
-        self.regs.pc += 2;
+        self.regs.pc = self.regs.pc.wrapping_add(2);
 
         self.set_reg8_by_id(dest_reg, value);
 
@@ -971,33 +1276,58 @@ impl Cpu {
     }
 
     fn ld_a_ind_nn(&mut self) -> usize {
-        let address = (self.mem.read(self.regs.pc+2) as u16)<<8 |  self.mem.read(self.regs.pc+1) as u16;
+        let address = self.read_imm16();
         trace!("{:04x}: LD A, (${:04x})", self.regs.pc, address);
-        self.regs.pc += 3;
+        self.regs.pc = self.regs.pc.wrapping_add(3);
 
-        self.regs.a = self.mem.read(address);
+        self.regs.a = self.read(address);
 
         16
     }
 
     fn decode_cb(&mut self) -> usize {
-        self.regs.pc += 1;
-        let instr = self.mem.read(self.regs.pc);
-
-        4 + match instr {
-            _ if instr&0xC0 == 0x00 => self.bc_alu((instr >> 3) & 0x07, instr & 0x07),
-            _ if instr&0xC0 == 0x40 => self.bit((instr >> 3) & 0x07, instr & 0x07),
-            _ if instr&0x80 == 0x80 => self.res_set(instr&0x40 == 0,(instr >> 3) & 0x07, instr & 0x07),
-            _ => {
-                trace!("\n{:?}", self.regs);
-                panic!("Uknown prefixed instruction op: 0x{:02x} at addr 0x{:04x}!", instr, self.regs.pc)
-            }
+        self.regs.pc = self.regs.pc.wrapping_add(1);
+        let instr = self.read(self.regs.pc);
+
+        4 + self.cb_handler(instr)(self, instr)
+    }
+
+    #[cfg(not(any(test, feature="bench")))]
+    fn cb_handler(&self, opcode: u8) -> Handler<B> {
+        Self::CB_OPCODES[opcode as usize]
+    }
+
+    #[cfg(any(test, feature="bench"))]
+    fn cb_handler(&self, opcode: u8) -> Handler<B> {
+        match self.dispatch {
+            Dispatch::Match => Self::cb_opcode_handler(opcode),
+            Dispatch::Table => Self::CB_OPCODES[opcode as usize],
+        }
+    }
+
+    // Prefixed opcode handlers indexed by the opcode after 0xCB
+    const fn cb_opcode_table() -> [Handler<B>; 256] {
+        let mut table: [Handler<B>; 256] = [|cpu, opcode| cpu.bc_alu((opcode >> 3) & 0x07, opcode & 0x07); 256];
+        let mut index = 0;
+        while index < 256 {
+            table[index] = Self::cb_opcode_handler(index as u8);
+            index += 1;
+        }
+        table
+    }
+
+    // Decode a prefixed opcode to its handler
+    const fn cb_opcode_handler(opcode: u8) -> Handler<B> {
+        match opcode {
+            _ if opcode&0xC0 == 0x00 => |cpu, op| cpu.bc_alu((op >> 3) & 0x07, op & 0x07),
+            _ if opcode&0xC0 == 0x40 => |cpu, op| cpu.bit((op >> 3) & 0x07, op & 0x07),
+            _ => |cpu, op| cpu.res_set(op&0x40 == 0, (op >> 3) & 0x07, op & 0x07),
         }
     }
 
     fn bc_alu(&mut self, operation: u8, reg_id:u8) -> usize {
-        trace!("{:04x}: {} {}", self.regs.pc-1, BCALU_NAMES[operation as usize], REG_NAMES[reg_id as usize]);
-        self.regs.pc += 1;
+        trace!("{:04x}: {} {}", self.regs.pc.wrapping_sub(1), BCALU_NAMES[operation as usize], REG_NAMES[reg_id as usize]);
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         let mut value = self.get_reg8_by_id(reg_id);
 
@@ -1057,7 +1387,7 @@ impl Cpu {
                 self.set_flag(SUBSTRACT_FLAG, false);
             },
             BCALU_SWAP => {
-                value = (value << 4) | (value >> 4);
+                value = value.rotate_right(4);
                 self.set_flag(HALF_CARRY_FLAG, false);
                 self.set_flag(SUBSTRACT_FLAG, false);
                 self.set_flag(CARRY_FLAG, false);
@@ -1078,8 +1408,8 @@ impl Cpu {
     }
 
     fn bit(&mut self, bit: u8, reg_id: u8) -> usize {
-        trace!("{:04x}: BIT {}, {}", self.regs.pc-1, bit, REG_NAMES[reg_id as usize]);
-        self.regs.pc += 1;
+        trace!("{:04x}: BIT {}, {}", self.regs.pc.wrapping_sub(1), bit, REG_NAMES[reg_id as usize]);
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         let flag = self.get_reg8_by_id(reg_id) & (1<<bit) == 0;
         self.set_flag(ZERO_FLAG, flag);
@@ -1090,9 +1420,9 @@ impl Cpu {
     }
 
     fn res_set(&mut self, res: bool, bit: u8, reg_id: u8) -> usize {
-        trace!("{:04x}: {} {}, {}", self.regs.pc-1, if res {"RES"} else {"SET"},
+        trace!("{:04x}: {} {}, {}", self.regs.pc.wrapping_sub(1), if res {"RES"} else {"SET"},
                                       bit, REG_NAMES[reg_id as usize]);
-        self.regs.pc += 1;
+        self.regs.pc = self.regs.pc.wrapping_add(1);
 
         let mut value = self.get_reg8_by_id(reg_id);
         if res {
@@ -1108,10 +1438,12 @@ impl Cpu {
 
 #[cfg(test)]
 mod tests {
-    use super::Cpu;
+    use super::{Cpu, Dispatch};
     use super::Regs;
     //use mem::Mem;
     use crate::bootstrap::Bootstrap;
+    use crate::bus::{Bus, FlatRam};
+    use crate::callstack::FrameKind;
     use crate::cart::Cart;
 
     fn test_cpu(instructions: &[u8], nstep: usize, expected: Regs) -> Cpu {
@@ -1147,12 +1479,305 @@ mod tests {
     #[test]
     fn ld_ind_a() {
         test_cpu(&[0x3E, 0x42, 0x77, 0x80, 0xff, 0xA7], 3, Regs {
-            a: 0, b: 0,
+            a: 0x42, b: 0,
             c: 0, d: 0,
             e: 0, f: 0,
             h: 0, l: 0,
-            pc: 1,
+            pc: 4,
             sp: 0,
         });
     }
+
+    fn flat_cpu(instructions: &[u8], nstep: usize) -> Cpu<FlatRam> {
+        let mut cpu = Cpu::with_bus(FlatRam::create_from_slice(instructions));
+        for _ in 0 .. nstep {
+            cpu.step();
+        }
+        cpu
+    }
+
+    #[test]
+    fn flat_ram_call_ret() {
+        // LD SP,$D000; CALL $0010; ... $0010: LD A,$42; RET
+        let mut program = vec![0x31, 0x00, 0xD0, 0xCD, 0x10, 0x00];
+        program.resize(0x10, 0);
+        program.extend_from_slice(&[0x3E, 0x42, 0xC9]);
+        let cpu = flat_cpu(&program, 4);
+
+        assert_eq!(cpu.regs.a, 0x42);
+        assert_eq!(cpu.regs.pc, 6);
+        assert_eq!(cpu.regs.sp, 0xD000);
+        assert_eq!(cpu.mem.memory[0xCFFE..0xD000], [0x06, 0x00]);
+    }
+
+    #[test]
+    fn call_stack() {
+        // LD SP,$D000; CALL $0010 ... $0010: RST $18 ... $0018: POP HL; RET
+        let mut program = vec![0x31, 0x00, 0xD0, 0xCD, 0x10, 0x00];
+        program.resize(0x10, 0);
+        program.extend_from_slice(&[0xDF, 0xC9]);
+        program.resize(0x18, 0);
+        program.extend_from_slice(&[0xE1, 0xC9]);
+        let mut cpu = flat_cpu(&program, 3);
+
+        assert_eq!(cpu.backtrace().len(), 2);
+        assert_eq!(cpu.backtrace()[1].kind, FrameKind::Rst);
+        assert_eq!(cpu.call_stack().format(cpu.regs.pc, 1, None), "#0   $0018 in $0018\n#1   $0010 in $0010\n#2   $0003\n");
+
+        // POP HL drops the return address of the RST
+        cpu.step();
+        assert_eq!(cpu.backtrace().len(), 1);
+        assert_eq!(cpu.call_stack().mismatches(), 1);
+
+        // RET returns from the CALL
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 0x06);
+        assert!(cpu.backtrace().is_empty());
+        assert_eq!(cpu.call_stack().mismatches(), 1);
+    }
+
+    // Run one instruction from a pseudo random state with the handler tables
+    // and with the opcodes decoded when executed
+    fn compare_dispatch(instruction: &[u8], seed: u32) {
+        let mut random = seed;
+        let mut next = move || {
+            random = random.wrapping_mul(1103515245).wrapping_add(12345);
+            (random >> 16) as u8
+        };
+
+        let mut memory: Vec<u8> = (0..0x10000).map(|_| next()).collect();
+        memory[0xffff] = 0;
+        let pc = u16::from_le_bytes([next(), next()]) & 0x7fff;
+        memory[pc as usize..pc as usize + instruction.len()].copy_from_slice(instruction);
+        let regs = [next(), next(), next(), next(), next(), next(), next(), next(), next(), next()];
+
+        let cpu = || {
+            let mut cpu = Cpu::with_bus(FlatRam::create_from_slice(&memory));
+            cpu.regs = Regs {
+                a: regs[0], b: regs[1], c: regs[2], d: regs[3], e: regs[4], f: regs[5]&0xf0, h: regs[6], l: regs[7],
+                pc, sp: u16::from_le_bytes([regs[8], regs[9]]),
+            };
+            cpu
+        };
+        let mut table = cpu();
+        table.step();
+        let mut reference = cpu();
+        reference.set_dispatch(Dispatch::Match);
+        reference.step();
+
+        let name = format!("{:02x?} seed {}", instruction, seed);
+        assert_eq!(table.regs, reference.regs, "{}", name);
+        assert_eq!(table.cycle, reference.cycle, "{}", name);
+        assert_eq!(table.mem.memory, reference.mem.memory, "{}", name);
+        assert_eq!((table.halted, table.stoped, table.locked, table.interrupts_enabled_next),
+                   (reference.halted, reference.stoped, reference.locked, reference.interrupts_enabled_next), "{}", name);
+    }
+
+    #[test]
+    fn dispatch_table_matches_decoder() {
+        for seed in 0..4 {
+            for opcode in 0..=0xFFu8 {
+                compare_dispatch(&[opcode], seed);
+                compare_dispatch(&[0xCB, opcode], seed);
+            }
+        }
+    }
+
+    #[test]
+    fn flat_ram_tick() {
+        // NOP; LD HL,$C000; INC (HL)
+        let cpu = flat_cpu(&[0x00, 0x21, 0x00, 0xC0, 0x34], 3);
+
+        assert_eq!(cpu.mem.memory[0xC000], 1);
+        assert_eq!(cpu.mem.cycles, cpu.get_cycle());
+        assert_eq!(cpu.mem.cycles, 4 + 12 + 12);
+    }
+
+    #[test]
+    fn pc_wrap_around() {
+        // JR +2 at $FFFE lands after the end of the address space
+        let mut cpu = Cpu::with_bus(FlatRam::new());
+        cpu.mem.memory[0xfffe] = 0x18;
+        cpu.mem.memory[0xffff] = 0x02;
+        cpu.regs.pc = 0xfffe;
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 0x0002);
+
+        // LD BC,$1234 at $FFFE reads its high byte at $0000
+        cpu.mem.memory[0xfffe] = 0x01;
+        cpu.mem.memory[0xffff] = 0x34;
+        cpu.mem.memory[0x0000] = 0x12;
+        cpu.regs.pc = 0xfffe;
+        cpu.step();
+        assert_eq!((cpu.regs.b, cpu.regs.c), (0x12, 0x34));
+        assert_eq!(cpu.regs.pc, 0x0001);
+    }
+
+    #[test]
+    fn hl_wrap_around() {
+        // LD (HL+),A; LD (HL-),A
+        let mut cpu = Cpu::with_bus(FlatRam::create_from_slice(&[0x22, 0x32]));
+        cpu.regs.a = 0x42;
+        cpu.regs.h = 0xff;
+        cpu.regs.l = 0xff;
+        cpu.step();
+        assert_eq!(cpu.mem.memory[0xffff], 0x42);
+        assert_eq!((cpu.regs.h, cpu.regs.l), (0x00, 0x00));
+
+        cpu.step();
+        assert_eq!(cpu.mem.memory[0x0000], 0x42);
+        assert_eq!((cpu.regs.h, cpu.regs.l), (0xff, 0xff));
+    }
+
+    fn interrupt_cpu(instructions: &[u8]) -> Cpu<FlatRam> {
+        let mut cpu = Cpu::with_bus(FlatRam::create_from_slice(instructions));
+        cpu.regs.sp = 0xD000;
+        cpu.mem.memory[0xffff] = 0x1f;
+        cpu
+    }
+
+    #[test]
+    fn interrupt_priority() {
+        let mut cpu = interrupt_cpu(&[0x00]);
+        cpu.set_ime(true);
+        cpu.mem.memory[0xff0f] = 0x14;
+        cpu.step();
+
+        assert_eq!(cpu.regs.pc, 0x50);
+        assert_eq!(cpu.mem.memory[0xff0f], 0x10);
+        assert!(!cpu.ime());
+        assert_eq!(cpu.get_cycle(), 20);
+        assert_eq!(cpu.regs.sp, 0xCFFE);
+    }
+
+    #[test]
+    fn ei_delay_and_di() {
+        // EI; NOP; NOP
+        let mut cpu = interrupt_cpu(&[0xFB, 0x00, 0x00]);
+        cpu.mem.memory[0xff0f] = 0x01;
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 2);
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 0x40);
+
+        // EI; DI; NOP
+        let mut cpu = interrupt_cpu(&[0xFB, 0xF3, 0x00]);
+        cpu.mem.memory[0xff0f] = 0x01;
+        for _ in 0..3 {
+            cpu.step();
+        }
+        assert_eq!(cpu.regs.pc, 3);
+        assert!(!cpu.ime());
+    }
+
+    #[test]
+    fn halt_wakeup_without_ime() {
+        // HALT; INC A
+        let mut cpu = interrupt_cpu(&[0x76, 0x3C]);
+        cpu.step();
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 1);
+
+        cpu.mem.memory[0xff0f] = 0x04;
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 2);
+        assert_eq!(cpu.regs.a, 1);
+        assert_eq!(cpu.mem.memory[0xff0f], 0x04);
+    }
+
+    #[test]
+    fn interrupt_cancelled_by_ie_push() {
+        // The high byte of PC ($02) is pushed to IE, disabling the VBlank interrupt
+        let mut cpu = interrupt_cpu(&[]);
+        cpu.regs.pc = 0x0200;
+        cpu.regs.sp = 0x0000;
+        cpu.set_ime(true);
+        cpu.mem.memory[0xff0f] = 0x01;
+        cpu.step();
+
+        assert_eq!(cpu.regs.pc, 0x0000);
+        assert_eq!(cpu.mem.memory[0xffff], 0x02);
+        assert_eq!(cpu.mem.memory[0xff0f], 0x01);
+        assert_eq!(cpu.get_cycle(), 20);
+    }
+
+    #[test]
+    fn halt_bug() {
+        // HALT; LD A,$14 is executed as LD A,$3E; INC D
+        let mut cpu = interrupt_cpu(&[0x76, 0x3E, 0x14]);
+        cpu.mem.memory[0xff0f] = 0x01;
+        for _ in 0..3 {
+            cpu.step();
+        }
+
+        assert_eq!(cpu.regs.a, 0x3E);
+        assert_eq!(cpu.regs.d, 1);
+        assert_eq!(cpu.regs.pc, 3);
+    }
+
+    #[test]
+    fn stop_until_joypad() {
+        // STOP; INC A
+        let mut cpu = interrupt_cpu(&[0x10, 0x00, 0x3C]);
+        cpu.mem.memory[0xff00] = 0xff;
+        cpu.mem.memory[0xff04] = 0x55;
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 2);
+        assert_eq!(cpu.mem.memory[0xff04], 0);
+        assert_eq!(cpu.mem.cycles, 8);
+
+        // Other interrupts do not leave STOP mode
+        cpu.mem.memory[0xff0f] = 0x01;
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 2);
+
+        cpu.mem.memory[0xff00] = 0xfe;
+        cpu.step();
+        assert_eq!(cpu.regs.pc, 3);
+        assert_eq!(cpu.regs.a, 1);
+    }
+
+    // Flat RAM recording the clock cycle at which every access happens
+    struct TimingBus {
+        ram: FlatRam,
+        accesses: Vec<(usize, u16)>,
+    }
+
+    impl Bus for TimingBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.accesses.push((self.ram.cycles, address));
+            self.ram.read(address)
+        }
+
+        fn write(&mut self, address: u16, data: u8) {
+            self.accesses.push((self.ram.cycles, address));
+            self.ram.write(address, data)
+        }
+
+        fn peek(&self, address: u16) -> u8 { self.ram.peek(address) }
+        fn tick(&mut self, cycles: usize) { self.ram.tick(cycles) }
+        fn pending_interrupts(&self) -> u8 { self.ram.pending_interrupts() }
+        fn acknowledge_interrupt(&mut self, interrupt: u8) { self.ram.acknowledge_interrupt(interrupt) }
+    }
+
+    #[test]
+    fn access_timing() {
+        // CALL $1234; PUSH BC; RET Z (not taken)
+        let mut ram = FlatRam::create_from_slice(&[0xCD, 0x34, 0x12]);
+        ram.memory[0x1234..0x1236].copy_from_slice(&[0xC5, 0xC8]);
+        let mut cpu = Cpu::with_bus(TimingBus { ram, accesses: Vec::new() });
+        cpu.regs.sp = 0xD000;
+        for _ in 0..3 {
+            cpu.step();
+        }
+
+        assert_eq!(cpu.mem.accesses, [
+            (4, 0x0000), (8, 0x0001), (12, 0x0002), (20, 0xCFFF), (24, 0xCFFE),
+            (28, 0x1234), (36, 0xCFFD), (40, 0xCFFC),
+            (44, 0x1235),
+        ]);
+        assert_eq!(cpu.mem.ram.cycles, 48);
+        assert_eq!(cpu.get_cycle(), 48);
+    }
 }
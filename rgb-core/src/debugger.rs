@@ -0,0 +1,255 @@
+// Debugger support
+//
+// Breakpoints stop the emulation before the instruction at their address is
+// executed, optionally only when a register matches a condition. Watchpoints
+// stop it after an instruction accessed memory in their address range, they
+// are checked by `Mem` on the CPU accesses only (not OAM DMA). Stepping runs
+// one instruction (into), over calls (over) or until the current function
+// returns (out).
+//
+// The debugger is driven through `Dmg::run_until_break()`, which returns why
+// the emulation stopped.
+
+use std::fmt;
+
+use crate::cpu::Regs;
+use crate::disasm::Instruction;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register { A, B, C, D, E, F, H, L, AF, BC, DE, HL, SP, PC }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison { Equal, NotEqual, Less, LessOrEqual, Greater, GreaterOrEqual }
+
+/// Register condition of a breakpoint, for example `A == $42`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub address: u16,
+    /// ROM bank of a 0x4000-0x7fff address, any bank if `None`
+    pub bank: Option<u16>,
+    pub condition: Option<Condition>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind { Read, Write, ReadWrite }
+
+/// Watch accesses to the addresses `start..=end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub kind: WatchKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Run one instruction
+    Into,
+    /// Run one instruction, running called functions entirely
+    Over,
+    /// Run until the current function returns
+    Out,
+}
+
+/// Why `Dmg::run_until_break()` stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// A video frame is ready to display, the emulation can be resumed
+    FrameReady,
+    /// PC reached a breakpoint, the instruction is not executed yet
+    Breakpoint(u16),
+    /// The last instruction accessed a watched address
+    Watchpoint { address: u16, value: u8, write: bool },
+    /// The requested step is complete
+    Step,
+}
+
+impl Register {
+    pub fn value(&self, regs: &Regs) -> u16 {
+        let pair = |high: u8, low: u8| (high as u16)<<8 | low as u16;
+        match self {
+            Register::A => regs.a as u16,
+            Register::B => regs.b as u16,
+            Register::C => regs.c as u16,
+            Register::D => regs.d as u16,
+            Register::E => regs.e as u16,
+            Register::F => regs.f as u16,
+            Register::H => regs.h as u16,
+            Register::L => regs.l as u16,
+            Register::AF => pair(regs.a, regs.f),
+            Register::BC => pair(regs.b, regs.c),
+            Register::DE => pair(regs.d, regs.e),
+            Register::HL => pair(regs.h, regs.l),
+            Register::SP => regs.sp,
+            Register::PC => regs.pc,
+        }
+    }
+}
+
+impl Condition {
+    pub fn matches(&self, regs: &Regs) -> bool {
+        let value = self.register.value(regs);
+        match self.comparison {
+            Comparison::Equal => value == self.value,
+            Comparison::NotEqual => value != self.value,
+            Comparison::Less => value < self.value,
+            Comparison::LessOrEqual => value <= self.value,
+            Comparison::Greater => value > self.value,
+            Comparison::GreaterOrEqual => value >= self.value,
+        }
+    }
+}
+
+impl Breakpoint {
+    pub fn new(address: u16) -> Breakpoint {
+        Breakpoint { address, bank: None, condition: None }
+    }
+
+    /// `rom_bank` is the bank mapped at 0x4000-0x7fff
+    pub fn matches(&self, regs: &Regs, rom_bank: usize) -> bool {
+        regs.pc == self.address && self.in_bank(rom_bank) && self.condition.is_none_or(|condition| condition.matches(regs))
+    }
+
+    fn in_bank(&self, rom_bank: usize) -> bool {
+        match (self.bank, self.address) {
+            (Some(bank), 0x4000..=0x7fff) => bank as usize == rom_bank,
+            _ => true,
+        }
+    }
+}
+
+impl Watchpoint {
+    pub fn matches(&self, address: u16, write: bool) -> bool {
+        let kind = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::ReadWrite => true,
+        };
+        kind && (self.start..=self.end).contains(&address)
+    }
+}
+
+/// Breakpoints and stepping state of the emulator
+#[derive(Default)]
+pub(crate) struct Debugger {
+    pub breakpoints: Vec<Breakpoint>,
+    step: Option<StepState>,
+    // Resuming from a breakpoint must execute its instruction
+    resume_address: Option<u16>,
+}
+
+#[derive(Clone, Copy)]
+enum StepState {
+    Into,
+    // Stop when PC reaches the return address with the stack back to its level
+    Until { pc: u16, sp: u16 },
+    // Stop after a return popping above the stack level
+    Out { sp: u16 },
+}
+
+impl Debugger {
+    pub fn start_step(&mut self, step: Step, regs: &Regs, instruction: Instruction, length: u16) {
+        let next = regs.pc.wrapping_add(length);
+        self.step = Some(match (step, instruction) {
+            (Step::Over, Instruction::Call(..)) | (Step::Over, Instruction::Rst(_)) => {
+                StepState::Until { pc: next, sp: regs.sp }
+            },
+            (Step::Out, _) => StepState::Out { sp: regs.sp },
+            _ => StepState::Into,
+        });
+    }
+
+    /// Breakpoint hit before executing the instruction at PC
+    pub fn check_breakpoint(&mut self, regs: &Regs, rom_bank: usize) -> Option<StopReason> {
+        // The marker is kept while PC stays on the breakpoint, in HALT
+        if self.resume_address == Some(regs.pc) {
+            return None;
+        }
+        self.resume_address = None;
+        if self.breakpoints.iter().any(|breakpoint| breakpoint.matches(regs, rom_bank)) {
+            self.resume_address = Some(regs.pc);
+            return Some(StopReason::Breakpoint(regs.pc));
+        }
+        None
+    }
+
+    pub fn is_stepping(&self) -> bool {
+        self.step.is_some()
+    }
+
+    /// Step completion after an instruction, `instruction` is the one executed
+    pub fn check_step(&mut self, regs: &Regs, instruction: Instruction) -> Option<StopReason> {
+        let done = match self.step? {
+            StepState::Into => true,
+            StepState::Until { pc, sp } => regs.pc == pc && regs.sp >= sp,
+            StepState::Out { sp } => {
+                matches!(instruction, Instruction::Ret(_) | Instruction::Reti) && regs.sp > sp
+            },
+        };
+        if done {
+            self.step = None;
+            Some(StopReason::Step)
+        } else {
+            None
+        }
+    }
+
+    pub fn clear_step(&mut self) {
+        self.step = None;
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+        })
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.bank, self.address) {
+            (Some(bank), 0x4000..=0x7fff) => write!(f, "${:02x}:{:04x}", bank, self.address)?,
+            _ => write!(f, "${:04x}", self.address)?,
+        }
+        if let Some(condition) = self.condition {
+            write!(f, " if {} {} ${:x}", condition.register, condition.comparison, condition.value)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for StopReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StopReason::FrameReady => write!(f, "Frame ready"),
+            StopReason::Breakpoint(address) => write!(f, "Breakpoint at ${:04x}", address),
+            StopReason::Watchpoint { address, value, write: true } => {
+                write!(f, "Watchpoint: wrote ${:02x} to ${:04x}", value, address)
+            },
+            StopReason::Watchpoint { address, value, write: false } => {
+                write!(f, "Watchpoint: read ${:02x} from ${:04x}", value, address)
+            },
+            StopReason::Step => write!(f, "Step"),
+        }
+    }
+}
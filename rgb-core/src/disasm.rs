@@ -0,0 +1,447 @@
+// SM83 disassembler
+//
+// Decodes the instruction at an address of a `Bus` into a structured
+// `Instruction`, which implements `Display` to produce the usual assembly
+// syntax, as used by the CPU trace. Memory is read with `Bus::peek()` so that
+// disassembling has no side effect on the emulation. With debug symbols,
+// `Instruction::with_symbols()` shows the address operands as labels.
+
+use std::fmt;
+
+use crate::bus::Bus;
+use crate::cpu::{ALU_NAMES, BCALU_NAMES, COND_NAMES, DD_NAMES, QQ_NAMES, REG_NAMES};
+use crate::symbols::Symbols;
+
+/// 8 bit operand, in the instruction encoding order
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg8 { B, C, D, E, H, L, IndHL, A }
+
+/// 16 bit register operand of loads and arithmetic
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg16 { BC, DE, HL, SP }
+
+/// 16 bit register operand of PUSH and POP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg16Stack { BC, DE, HL, AF }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition { NZ, Z, NC, C }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AluOp { Add, Adc, Sub, Sbc, And, Xor, Or, Cp }
+
+/// Rotate and shift operations of the CB prefixed instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShiftOp { Rlc, Rrc, Rl, Rr, Sla, Sra, Swap, Srl }
+
+/// Indirect addressing of the A register loads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Indirect { BC, DE, HLIncrement, HLDecrement }
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    Stop,
+    Halt,
+    Di,
+    Ei,
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+    /// LD r, r
+    Ld(Reg8, Reg8),
+    /// LD r, n
+    LdImm(Reg8, u8),
+    /// LD dd, nn
+    LdImm16(Reg16, u16),
+    /// LD (BC/DE/HL+/HL-), A
+    StoreA(Indirect),
+    /// LD A, (BC/DE/HL+/HL-)
+    LoadA(Indirect),
+    /// LD (nn), A
+    StoreAAbsolute(u16),
+    /// LD A, (nn)
+    LoadAAbsolute(u16),
+    /// LD (nn), SP
+    StoreSp(u16),
+    /// LD ($FF00+n), A
+    StoreHigh(u8),
+    /// LD A, ($FF00+n)
+    LoadHigh(u8),
+    /// LD ($FF00+C), A
+    StoreHighC,
+    /// LD A, ($FF00+C)
+    LoadHighC,
+    LdSpHl,
+    /// LD HL, SP+e
+    LdHlSpOffset(i8),
+    /// ADD SP, e
+    AddSpOffset(i8),
+    Alu(AluOp, Reg8),
+    AluImm(AluOp, u8),
+    Inc(Reg8),
+    Dec(Reg8),
+    Inc16(Reg16),
+    Dec16(Reg16),
+    AddHl(Reg16),
+    Jp(Option<Condition>, u16),
+    JpHl,
+    /// JR with the destination address
+    Jr(Option<Condition>, u16),
+    Call(Option<Condition>, u16),
+    Ret(Option<Condition>),
+    Reti,
+    Rst(u8),
+    Push(Reg16Stack),
+    Pop(Reg16Stack),
+    Shift(ShiftOp, Reg8),
+    Bit(u8, Reg8),
+    Res(u8, Reg8),
+    Set(u8, Reg8),
+    /// One of the 11 opcodes locking the CPU
+    Illegal(u8),
+}
+
+/// Decode the instruction at `address`, returns it with its length in bytes
+pub fn disassemble<B: Bus + ?Sized>(bus: &B, address: u16) -> (Instruction, u16) {
+    let opcode = bus.peek(address);
+    let imm8 = bus.peek(address.wrapping_add(1));
+    let imm16 = (bus.peek(address.wrapping_add(2)) as u16)<<8 | imm8 as u16;
+    let relative = address.wrapping_add(2).wrapping_add(imm8 as i8 as u16);
+
+    let r = |id: u8| Reg8::from_id(id&0x07);
+    let dd = Reg16::from_id((opcode>>4)&0x03);
+    let qq = Reg16Stack::from_id((opcode>>4)&0x03);
+    let cond = Condition::from_id((opcode>>3)&0x03);
+    let alu = AluOp::from_id((opcode>>3)&0x07);
+
+    match opcode {
+        0x00 => (Instruction::Nop, 1),
+        0x10 => (Instruction::Stop, 2),
+        0x76 => (Instruction::Halt, 1),
+        0xF3 => (Instruction::Di, 1),
+        0xFB => (Instruction::Ei, 1),
+        0x27 => (Instruction::Daa, 1),
+        0x2F => (Instruction::Cpl, 1),
+        0x37 => (Instruction::Scf, 1),
+        0x3F => (Instruction::Ccf, 1),
+        0x07 => (Instruction::Rlca, 1),
+        0x0F => (Instruction::Rrca, 1),
+        0x17 => (Instruction::Rla, 1),
+        0x1F => (Instruction::Rra, 1),
+        0x08 => (Instruction::StoreSp(imm16), 3),
+        0x18 => (Instruction::Jr(None, relative), 2),
+        0xC3 => (Instruction::Jp(None, imm16), 3),
+        0xE9 => (Instruction::JpHl, 1),
+        0xCD => (Instruction::Call(None, imm16), 3),
+        0xC9 => (Instruction::Ret(None), 1),
+        0xD9 => (Instruction::Reti, 1),
+        0xE0 => (Instruction::StoreHigh(imm8), 2),
+        0xF0 => (Instruction::LoadHigh(imm8), 2),
+        0xE2 => (Instruction::StoreHighC, 1),
+        0xF2 => (Instruction::LoadHighC, 1),
+        0xEA => (Instruction::StoreAAbsolute(imm16), 3),
+        0xFA => (Instruction::LoadAAbsolute(imm16), 3),
+        0xE8 => (Instruction::AddSpOffset(imm8 as i8), 2),
+        0xF8 => (Instruction::LdHlSpOffset(imm8 as i8), 2),
+        0xF9 => (Instruction::LdSpHl, 1),
+        0xCB => (decode_cb(bus.peek(address.wrapping_add(1))), 2),
+        0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => (Instruction::Illegal(opcode), 1),
+        _ if opcode&0xE7 == 0x20 => (Instruction::Jr(Some(cond), relative), 2),
+        _ if opcode&0xCF == 0x01 => (Instruction::LdImm16(dd, imm16), 3),
+        _ if opcode&0xC7 == 0x02 => {
+            let indirect = Indirect::from_id((opcode>>4)&0x03);
+            if opcode&0x08 == 0 {
+                (Instruction::StoreA(indirect), 1)
+            } else {
+                (Instruction::LoadA(indirect), 1)
+            }
+        },
+        _ if opcode&0xCF == 0x03 => (Instruction::Inc16(dd), 1),
+        _ if opcode&0xCF == 0x0B => (Instruction::Dec16(dd), 1),
+        _ if opcode&0xC7 == 0x04 => (Instruction::Inc(r(opcode>>3)), 1),
+        _ if opcode&0xC7 == 0x05 => (Instruction::Dec(r(opcode>>3)), 1),
+        _ if opcode&0xC7 == 0x06 => (Instruction::LdImm(r(opcode>>3), imm8), 2),
+        _ if opcode&0xCF == 0x09 => (Instruction::AddHl(dd), 1),
+        _ if opcode&0xC0 == 0x40 => (Instruction::Ld(r(opcode>>3), r(opcode)), 1),
+        _ if opcode&0xC0 == 0x80 => (Instruction::Alu(alu, r(opcode)), 1),
+        _ if opcode&0xC7 == 0xC6 => (Instruction::AluImm(alu, imm8), 2),
+        _ if opcode&0xE7 == 0xC0 => (Instruction::Ret(Some(cond)), 1),
+        _ if opcode&0xE7 == 0xC2 => (Instruction::Jp(Some(cond), imm16), 3),
+        _ if opcode&0xE7 == 0xC4 => (Instruction::Call(Some(cond), imm16), 3),
+        _ if opcode&0xCF == 0xC1 => (Instruction::Pop(qq), 1),
+        _ if opcode&0xCF == 0xC5 => (Instruction::Push(qq), 1),
+        _ if opcode&0xC7 == 0xC7 => (Instruction::Rst(opcode&0x38), 1),
+        _ => panic!("Disassembler decoding bug: 0x{:02x}", opcode),
+    }
+}
+
+fn decode_cb(opcode: u8) -> Instruction {
+    let reg = Reg8::from_id(opcode&0x07);
+    let bit = (opcode>>3)&0x07;
+
+    match opcode&0xC0 {
+        0x00 => Instruction::Shift(ShiftOp::from_id(bit), reg),
+        0x40 => Instruction::Bit(bit, reg),
+        0x80 => Instruction::Res(bit, reg),
+        _ => Instruction::Set(bit, reg),
+    }
+}
+
+impl Reg8 {
+    fn from_id(id: u8) -> Reg8 {
+        [Reg8::B, Reg8::C, Reg8::D, Reg8::E, Reg8::H, Reg8::L, Reg8::IndHL, Reg8::A][id as usize]
+    }
+}
+
+impl Reg16 {
+    fn from_id(id: u8) -> Reg16 {
+        [Reg16::BC, Reg16::DE, Reg16::HL, Reg16::SP][id as usize]
+    }
+}
+
+impl Reg16Stack {
+    fn from_id(id: u8) -> Reg16Stack {
+        [Reg16Stack::BC, Reg16Stack::DE, Reg16Stack::HL, Reg16Stack::AF][id as usize]
+    }
+}
+
+impl Condition {
+    fn from_id(id: u8) -> Condition {
+        [Condition::NZ, Condition::Z, Condition::NC, Condition::C][id as usize]
+    }
+}
+
+impl AluOp {
+    fn from_id(id: u8) -> AluOp {
+        [AluOp::Add, AluOp::Adc, AluOp::Sub, AluOp::Sbc, AluOp::And, AluOp::Xor, AluOp::Or, AluOp::Cp][id as usize]
+    }
+}
+
+impl ShiftOp {
+    fn from_id(id: u8) -> ShiftOp {
+        [ShiftOp::Rlc, ShiftOp::Rrc, ShiftOp::Rl, ShiftOp::Rr,
+         ShiftOp::Sla, ShiftOp::Sra, ShiftOp::Swap, ShiftOp::Srl][id as usize]
+    }
+}
+
+impl Indirect {
+    fn from_id(id: u8) -> Indirect {
+        [Indirect::BC, Indirect::DE, Indirect::HLIncrement, Indirect::HLDecrement][id as usize]
+    }
+}
+
+impl fmt::Display for Reg8 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(REG_NAMES[*self as usize]) }
+}
+
+impl fmt::Display for Reg16 {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(DD_NAMES[*self as usize]) }
+}
+
+impl fmt::Display for Reg16Stack {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(QQ_NAMES[*self as usize]) }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(COND_NAMES[*self as usize]) }
+}
+
+impl fmt::Display for AluOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(ALU_NAMES[*self as usize]) }
+}
+
+impl fmt::Display for ShiftOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { f.write_str(BCALU_NAMES[*self as usize]) }
+}
+
+impl fmt::Display for Indirect {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Indirect::BC => "(BC)",
+            Indirect::DE => "(DE)",
+            Indirect::HLIncrement => "(HL+)",
+            Indirect::HLDecrement => "(HL-)",
+        })
+    }
+}
+
+/// Instruction displayed with its address operand as `label+offset`
+pub struct SymbolicInstruction<'a> {
+    instruction: Instruction,
+    symbols: &'a Symbols,
+    rom_bank: usize,
+}
+
+impl Instruction {
+    /// Display the address operand with the closest label, `rom_bank` is the
+    /// bank mapped at 0x4000-0x7fff
+    pub fn with_symbols(self, symbols: &Symbols, rom_bank: usize) -> SymbolicInstruction<'_> {
+        SymbolicInstruction { instruction: self, symbols, rom_bank }
+    }
+}
+
+impl SymbolicInstruction<'_> {
+    fn address(&self, address: u16) -> String {
+        match self.symbols.lookup(address, self.rom_bank) {
+            Some(location) => location.to_string(),
+            None => format!("${:04x}", address),
+        }
+    }
+}
+
+impl fmt::Display for SymbolicInstruction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.instruction {
+            Instruction::Jp(condition, address) => write!(f, "JP {}{}", Cond(condition), self.address(address)),
+            Instruction::Jr(condition, address) => write!(f, "JR {}{}", Cond(condition), self.address(address)),
+            Instruction::Call(condition, address) => write!(f, "CALL {}{}", Cond(condition), self.address(address)),
+            Instruction::StoreAAbsolute(address) => write!(f, "LD ({}), A", self.address(address)),
+            Instruction::LoadAAbsolute(address) => write!(f, "LD A, ({})", self.address(address)),
+            Instruction::StoreSp(address) => write!(f, "LD ({}), SP", self.address(address)),
+            Instruction::StoreHigh(offset) => write!(f, "LD ({}), A", self.address(0xff00 | offset as u16)),
+            Instruction::LoadHigh(offset) => write!(f, "LD A, ({})", self.address(0xff00 | offset as u16)),
+            // Immediate values are often constants, only exact labels are shown
+            Instruction::LdImm16(dest, value) => match self.symbols.lookup(value, self.rom_bank) {
+                Some(location) if location.offset == 0 => write!(f, "LD {}, {}", dest, location),
+                _ => write!(f, "{}", self.instruction),
+            },
+            instruction => write!(f, "{}", instruction),
+        }
+    }
+}
+
+// Condition prefix of jumps, calls and returns
+struct Cond(Option<Condition>);
+
+impl fmt::Display for Cond {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(condition) => write!(f, "{}, ", condition),
+            None => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Instruction::Nop => write!(f, "NOP"),
+            Instruction::Stop => write!(f, "STOP"),
+            Instruction::Halt => write!(f, "HALT"),
+            Instruction::Di => write!(f, "DI"),
+            Instruction::Ei => write!(f, "EI"),
+            Instruction::Daa => write!(f, "DAA"),
+            Instruction::Cpl => write!(f, "CPL"),
+            Instruction::Scf => write!(f, "SCF"),
+            Instruction::Ccf => write!(f, "CCF"),
+            Instruction::Rlca => write!(f, "RLCA"),
+            Instruction::Rrca => write!(f, "RRCA"),
+            Instruction::Rla => write!(f, "RLA"),
+            Instruction::Rra => write!(f, "RRA"),
+            Instruction::Ld(dest, src) => write!(f, "LD {}, {}", dest, src),
+            Instruction::LdImm(dest, value) => write!(f, "LD {}, ${:02x}", dest, value),
+            Instruction::LdImm16(dest, value) => write!(f, "LD {}, ${:04x}", dest, value),
+            Instruction::StoreA(indirect) => write!(f, "LD {}, A", indirect),
+            Instruction::LoadA(indirect) => write!(f, "LD A, {}", indirect),
+            Instruction::StoreAAbsolute(address) => write!(f, "LD (${:04x}), A", address),
+            Instruction::LoadAAbsolute(address) => write!(f, "LD A, (${:04x})", address),
+            Instruction::StoreSp(address) => write!(f, "LD (${:04x}), SP", address),
+            Instruction::StoreHigh(address) => write!(f, "LD ($FF00+${:02x}), A", address),
+            Instruction::LoadHigh(address) => write!(f, "LD A, ($FF00+${:02x})", address),
+            Instruction::StoreHighC => write!(f, "LD ($FF00+C), A"),
+            Instruction::LoadHighC => write!(f, "LD A, ($FF00+C)"),
+            Instruction::LdSpHl => write!(f, "LD SP, HL"),
+            Instruction::LdHlSpOffset(offset) => write!(f, "LD HL, SP{:+}", offset),
+            Instruction::AddSpOffset(offset) => write!(f, "ADD SP, {}", offset),
+            Instruction::Alu(operation, reg) => write!(f, "{} {}", operation, reg),
+            Instruction::AluImm(operation, value) => write!(f, "{} ${:02x}", operation, value),
+            Instruction::Inc(reg) => write!(f, "INC {}", reg),
+            Instruction::Dec(reg) => write!(f, "DEC {}", reg),
+            Instruction::Inc16(reg) => write!(f, "INC {}", reg),
+            Instruction::Dec16(reg) => write!(f, "DEC {}", reg),
+            Instruction::AddHl(reg) => write!(f, "ADD HL, {}", reg),
+            Instruction::Jp(condition, address) => write!(f, "JP {}${:04x}", Cond(condition), address),
+            Instruction::JpHl => write!(f, "JP (HL)"),
+            Instruction::Jr(condition, address) => write!(f, "JR {}${:04x}", Cond(condition), address),
+            Instruction::Call(condition, address) => write!(f, "CALL {}${:04x}", Cond(condition), address),
+            Instruction::Ret(Some(condition)) => write!(f, "RET {}", condition),
+            Instruction::Ret(None) => write!(f, "RET"),
+            Instruction::Reti => write!(f, "RETI"),
+            Instruction::Rst(address) => write!(f, "RST ${:02x}", address),
+            Instruction::Push(reg) => write!(f, "PUSH {}", reg),
+            Instruction::Pop(reg) => write!(f, "POP {}", reg),
+            Instruction::Shift(operation, reg) => write!(f, "{} {}", operation, reg),
+            Instruction::Bit(bit, reg) => write!(f, "BIT {}, {}", bit, reg),
+            Instruction::Res(bit, reg) => write!(f, "RES {}, {}", bit, reg),
+            Instruction::Set(bit, reg) => write!(f, "SET {}, {}", bit, reg),
+            Instruction::Illegal(opcode) => write!(f, "DB ${:02x}", opcode),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::FlatRam;
+
+    fn disasm(bytes: &[u8]) -> (String, u16) {
+        let ram = FlatRam::create_from_slice(bytes);
+        let (instruction, length) = disassemble(&ram, 0);
+        (instruction.to_string(), length)
+    }
+
+    #[test]
+    fn base_opcodes() {
+        assert_eq!(disasm(&[0x00]), ("NOP".to_string(), 1));
+        assert_eq!(disasm(&[0x31, 0xFE, 0xFF]), ("LD SP, $fffe".to_string(), 3));
+        assert_eq!(disasm(&[0x3A]), ("LD A, (HL-)".to_string(), 1));
+        assert_eq!(disasm(&[0x36, 0x12]), ("LD (HL), $12".to_string(), 2));
+        assert_eq!(disasm(&[0x20, 0xFE]), ("JR NZ, $0000".to_string(), 2));
+        assert_eq!(disasm(&[0xAF]), ("XOR A".to_string(), 1));
+        assert_eq!(disasm(&[0xE0, 0x40]), ("LD ($FF00+$40), A".to_string(), 2));
+        assert_eq!(disasm(&[0xF8, 0xFE]), ("LD HL, SP-2".to_string(), 2));
+        assert_eq!(disasm(&[0xC4, 0x34, 0x12]), ("CALL NZ, $1234".to_string(), 3));
+        assert_eq!(disasm(&[0xF1]), ("POP AF".to_string(), 1));
+        assert_eq!(disasm(&[0xFF]), ("RST $38".to_string(), 1));
+        assert_eq!(disasm(&[0xDD]), ("DB $dd".to_string(), 1));
+    }
+
+    #[test]
+    fn cb_opcodes() {
+        assert_eq!(disasm(&[0xCB, 0x37]), ("SWAP A".to_string(), 2));
+        assert_eq!(disasm(&[0xCB, 0x7E]), ("BIT 7, (HL)".to_string(), 2));
+        assert_eq!(disasm(&[0xCB, 0x87]), ("RES 0, A".to_string(), 2));
+        assert_eq!(disasm(&[0xCB, 0xFF]), ("SET 7, A".to_string(), 2));
+    }
+
+    #[test]
+    fn symbolic_operands() {
+        let symbols = Symbols::parse("00:0150 Main\n01:4000 Banked\n00:c000 wCounter\n00:ff80 hFlags\n").unwrap();
+        let disasm = |bytes: &[u8]| {
+            let (instruction, _) = disassemble(&FlatRam::create_from_slice(bytes), 0);
+            instruction.with_symbols(&symbols, 1).to_string()
+        };
+
+        assert_eq!(disasm(&[0xCD, 0x50, 0x01]), "CALL Main");
+        assert_eq!(disasm(&[0xC2, 0x03, 0x40]), "JP NZ, Banked+$3");
+        assert_eq!(disasm(&[0xEA, 0x01, 0xC0]), "LD (wCounter+$1), A");
+        assert_eq!(disasm(&[0xF0, 0x80]), "LD A, (hFlags)");
+        assert_eq!(disasm(&[0x21, 0x00, 0xC0]), "LD HL, wCounter");
+        assert_eq!(disasm(&[0x21, 0x01, 0xC0]), "LD HL, $c001");
+        assert_eq!(disasm(&[0xC3, 0x00, 0x01]), "JP $0100");
+    }
+
+    #[test]
+    fn all_opcodes_decode() {
+        for opcode in 0..=0xFFu8 {
+            let (instruction, length) = disassemble(&FlatRam::create_from_slice(&[opcode]), 0);
+            assert!((1..=3).contains(&length), "{:02x}: {}", opcode, instruction);
+        }
+    }
+}
@@ -2,7 +2,16 @@
 use crate::cart::Cart;
 use crate::cpu::Cpu;
 use crate::bootstrap::Bootstrap;
+use crate::coverage::Coverage;
+use crate::debugger::{Breakpoint, Debugger, Step, StopReason, Watchpoint};
+use crate::disasm;
+use crate::error::EmulationError;
 use crate::joypad;
+use crate::savestate::{StateLoadError, StateReader, StateWriter};
+use crate::profile::Profiler;
+use crate::rewind::Rewind;
+use crate::symbols::{Location, Symbols};
+use crate::trace::Tracer;
 
 /// DMG emulator
 ///
@@ -14,6 +23,9 @@ use crate::joypad;
 /// internal memories).
 pub struct Dmg {
     pub cpu: Cpu,
+    rewind: Option<Rewind>,
+    debugger: Debugger,
+    symbols: Option<Symbols>,
 }
 
 impl Dmg {
@@ -26,29 +38,212 @@ impl Dmg {
     pub fn new_with_bootstrap(cart: Cart, bootstrap: Bootstrap) -> Self {
         let cpu = Cpu::new(bootstrap, cart);
 
-        Self { cpu }
+        Self { cpu, rewind: None, debugger: Debugger::default(), symbols: None }
     }
 
     /// Step the emulation one step
     ///
     /// This will run one CPU instruction, this means that it can result in 
-    /// running more than one clock cycles.
+    /// running more than one clock cycles. The peripherals are run by the CPU
+    /// memory accesses during the instruction.
     ///
-    /// Return `true` if an new video frame is ready to display on that step.
-    pub fn step(&mut self) -> bool {
+    /// Return `true` if an new video frame is ready to display on that step,
+    /// or the error raised while running the instruction. After an illegal
+    /// opcode the CPU is locked but the emulation can still be stepped.
+    pub fn step(&mut self) -> Result<bool, EmulationError> {
+        if self.cpu.mem.coverage.is_some() {
+            let pc = self.cpu.get_pc();
+            let (_, length) = disasm::disassemble(&self.cpu.mem, pc);
+            if let Some(coverage) = &mut self.cpu.mem.coverage {
+                coverage.set_fetch(pc, length);
+            }
+        }
+
+        self.cpu.set_rom_bank(self.cpu.mem.cart.rom_bank());
         self.cpu.step();
-        self.cpu.mem.step();
-        self.cpu.mem.reg_if |= self.cpu.mem.timer.step(self.cpu.cycle);
-        self.cpu.mem.reg_if |= self.cpu.mem.video.step(self.cpu.cycle);
-        self.cpu.mem.joypad.step();
-        self.cpu.mem.audio.step(self.cpu.cycle);
 
-        self.cpu.mem.video.image_ready
+        if let Some(error) = self.cpu.take_error().or_else(|| self.cpu.mem.cart.take_error()) {
+            return Err(error);
+        }
+
+        Ok(std::mem::take(&mut self.cpu.mem.frame_ready))
     }
 
     /// Runs the emulation until a frame becomes available to display
-    pub fn run_until_next_frame(&mut self) {
-        while !self.step() {}
+    ///
+    /// If rewind is enabled, a snapshot of the state is recorded for each frame.
+    /// The emulation stops early if an error is raised.
+    pub fn run_until_next_frame(&mut self) -> Result<(), EmulationError> {
+        while !self.step()? {}
+        self.frame_done();
+
+        Ok(())
+    }
+
+    /// Runs the emulation until a frame is ready or the debugger stops it
+    ///
+    /// Breakpoints are checked before executing each instruction, resuming
+    /// from a breakpoint executes its instruction. Watchpoints and steps stop
+    /// the emulation after the instruction. A rewind snapshot is recorded for
+    /// each frame like in `run_until_next_frame()`.
+    pub fn run_until_break(&mut self) -> Result<StopReason, EmulationError> {
+        // Drop the hits of accesses made by `step()` or `run_until_next_frame()`
+        self.cpu.mem.watchpoint_hit = None;
+        loop {
+            if !self.debugger.breakpoints.is_empty() {
+                if let Some(reason) = self.debugger.check_breakpoint(self.cpu.regs(), self.cpu.mem.cart.rom_bank()) {
+                    self.debugger.clear_step();
+                    return Ok(reason);
+                }
+            }
+
+            let instruction = if self.debugger.is_stepping() {
+                Some(disasm::disassemble(&self.cpu.mem, self.cpu.get_pc()).0)
+            } else {
+                None
+            };
+            let frame = self.step()?;
+
+            if let Some(reason) = self.cpu.mem.watchpoint_hit.take() {
+                self.debugger.clear_step();
+                return Ok(reason);
+            }
+            if let Some(instruction) = instruction {
+                if let Some(reason) = self.debugger.check_step(self.cpu.regs(), instruction) {
+                    return Ok(reason);
+                }
+            }
+            if frame {
+                self.frame_done();
+                return Ok(StopReason::FrameReady);
+            }
+        }
+    }
+
+    fn frame_done(&mut self) {
+        if self.rewind.is_some() {
+            let snapshot = self.snapshot();
+            if let Some(rewind) = &mut self.rewind {
+                rewind.push(snapshot);
+            }
+        }
+    }
+
+    /// Log each executed instruction with a tracer, or stop logging with `None`
+    ///
+    /// Returns the previous tracer, which can be finished to flush the log.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) -> Option<Tracer> {
+        self.cpu.set_tracer(tracer)
+    }
+
+    /// Profile the execution, or stop profiling with `None`
+    ///
+    /// Returns the previous profiler, with the profile recorded so far.
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) -> Option<Profiler> {
+        self.cpu.set_profiler(profiler)
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.cpu.profiler()
+    }
+
+    /// Set the debug symbols of the ROM, used by the debugger output
+    pub fn set_symbols(&mut self, symbols: Option<Symbols>) {
+        self.symbols = symbols;
+    }
+
+    pub fn symbols(&self) -> Option<&Symbols> {
+        self.symbols.as_ref()
+    }
+
+    /// Label of an address in the current memory mapping, if symbols are set
+    pub fn location(&self, address: u16) -> Option<Location<'_>> {
+        self.symbols.as_ref()?.lookup(address, self.cpu.mem.cart.rom_bank())
+    }
+
+    /// Print the shadow call stack, with labels if symbols are set
+    pub fn print_backtrace(&self) {
+        print!("{}", self.cpu.call_stack().format(self.cpu.get_pc(), self.cpu.mem.cart.rom_bank(), self.symbols()));
+    }
+
+    /// Request a step, completed by `run_until_break()`
+    pub fn debug_step(&mut self, step: Step) {
+        let (instruction, length) = disasm::disassemble(&self.cpu.mem, self.cpu.get_pc());
+        self.debugger.start_step(step, self.cpu.regs(), instruction, length);
+    }
+
+    pub fn add_breakpoint(&mut self, breakpoint: Breakpoint) {
+        self.debugger.breakpoints.push(breakpoint);
+    }
+
+    /// Remove the breakpoints at an address, returns `false` if there was none
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        let count = self.debugger.breakpoints.len();
+        self.debugger.breakpoints.retain(|breakpoint| breakpoint.address != address);
+        self.debugger.breakpoints.len() != count
+    }
+
+    pub fn breakpoints(&self) -> &[Breakpoint] {
+        &self.debugger.breakpoints
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        self.cpu.mem.watchpoints.push(watchpoint);
+    }
+
+    /// Remove the watchpoint at `index` in `watchpoints()`
+    pub fn remove_watchpoint(&mut self, index: usize) -> Option<Watchpoint> {
+        if index < self.cpu.mem.watchpoints.len() {
+            Some(self.cpu.mem.watchpoints.remove(index))
+        } else {
+            None
+        }
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.cpu.mem.watchpoints
+    }
+
+    /// Start recording the cart code coverage
+    ///
+    /// Only the accesses made through `step()` are told apart as executed
+    /// code or data.
+    pub fn enable_coverage(&mut self) {
+        self.cpu.mem.coverage = Some(Coverage::new(&self.cpu.mem.cart));
+    }
+
+    /// Stop recording the coverage, returns the coverage recorded
+    pub fn disable_coverage(&mut self) -> Option<Coverage> {
+        self.cpu.mem.coverage.take()
+    }
+
+    pub fn coverage(&self) -> Option<&Coverage> {
+        self.cpu.mem.coverage.as_ref()
+    }
+
+    /// Enable rewinding up to `frames` frames back
+    pub fn enable_rewind(&mut self, frames: usize) {
+        self.rewind = Some(Rewind::new(frames));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    /// Rewind the emulation `frames` frames back
+    ///
+    /// Returns the number of frames actually rewound, which is lower than
+    /// requested when reaching the beginning of the rewind buffer.
+    pub fn rewind_frames(&mut self, frames: usize) -> usize {
+        let snapshot = self.rewind.as_mut().and_then(|rewind| rewind.rewind(frames));
+        match snapshot {
+            Some((rewound, snapshot)) => {
+                let snapshot = snapshot.to_vec();
+                self.restore_state(StateReader::snapshot(&snapshot)).expect("Rewind snapshot is invalid");
+                rewound
+            },
+            None => 0,
+        }
     }
 
     /// Returns a reference to the display framebuffer
@@ -61,8 +256,364 @@ impl Dmg {
         self.cpu.reset();
     }
 
+    /// Serialize the complete emulator state
+    ///
+    /// The returned buffer can later be restored with `load_state()` on an
+    /// emulator running the same ROM. The cart ROM and the bootstrap are not
+    /// part of the state.
+    pub fn save_state(&self) -> Vec<u8> {
+        self.write_state(StateWriter::new())
+    }
+
+    // State restored by the emulator itself, not timestamped
+    fn snapshot(&self) -> Vec<u8> {
+        self.write_state(StateWriter::snapshot())
+    }
+
+    fn write_state(&self, mut state: StateWriter) -> Vec<u8> {
+        state.write_header(self.cpu.mem.cart.rom_id());
+        self.cpu.save_state(&mut state);
+        state.into_inner()
+    }
+
+    /// Restore an emulator state previously created by `save_state()`
+    ///
+    /// If the state cannot be loaded, an error is returned and the emulator
+    /// is left untouched. Otherwise the rewind history is cleared, as it
+    /// leads to the state the emulator was in before the load.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateLoadError> {
+        self.restore_state(StateReader::new(data))?;
+        if let Some(rewind) = &mut self.rewind {
+            rewind.clear();
+        }
+        Ok(())
+    }
+
+    // Restore a state, keeping the rewind history
+    fn restore_state(&mut self, mut state: StateReader) -> Result<(), StateLoadError> {
+        state.read_header(self.cpu.mem.cart.rom_id())?;
+
+        let backup = self.snapshot();
+        let result = self.cpu.load_state(&mut state).and_then(|_| state.finish());
+        if result.is_err() {
+            let mut backup = StateReader::snapshot(&backup);
+            backup.read_header(self.cpu.mem.cart.rom_id())
+                  .and_then(|_| self.cpu.load_state(&mut backup))
+                  .expect("Restoring the emulator state failed");
+        }
+        result
+    }
+
     /// Set new state for an input button
     pub fn set_button(&mut self, button: joypad::JoypadButton, pressed: bool) {
         self.cpu.mem.joypad.set_button(button, pressed);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::Dmg;
+    use crate::debugger::{Breakpoint, Comparison, Condition, Register, Step, StopReason, WatchKind, Watchpoint};
+    use crate::error::EmulationError;
+    use crate::cart::Cart;
+    use crate::coverage::{BankSummary, EXEC, READ, WRITE};
+    use crate::profile::{Counts, Profiler};
+    use crate::symbols::Symbols;
+    use crate::trace::Tracer;
+
+    fn test_dmg() -> Dmg {
+        // LD HL, $C000; loop: INC (HL); JR loop
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x106].copy_from_slice(&[0x21, 0x00, 0xC0, 0x34, 0x18, 0xFD]);
+        Dmg::new(Cart::create_from_slice(&rom))
+    }
+
+    fn work_ram(dmg: &Dmg) -> u8 {
+        dmg.cpu.mem.work[0]
+    }
+
+    fn run(dmg: &mut Dmg, steps: usize) {
+        for _ in 0..steps {
+            dmg.step().unwrap();
+        }
+    }
+
+    #[test]
+    fn save_load_state() {
+        let mut dmg = test_dmg();
+        run(&mut dmg, 1000);
+        let state = dmg.save_state();
+
+        run(&mut dmg, 1000);
+        let expected = dmg.save_state();
+
+        dmg.load_state(&state).unwrap();
+        assert_eq!(dmg.save_state(), state);
+        run(&mut dmg, 1000);
+        assert_eq!(dmg.save_state(), expected);
+    }
+
+    #[test]
+    fn reject_invalid_state() {
+        let mut dmg = test_dmg();
+        run(&mut dmg, 1000);
+        let state = dmg.save_state();
+        run(&mut dmg, 1000);
+        let current = dmg.save_state();
+
+        let mut bad_version = state.clone();
+        bad_version[4] = bad_version[4].wrapping_add(1);
+        assert!(dmg.load_state(&bad_version).is_err());
+        assert!(dmg.load_state(&state[..state.len()-1]).is_err());
+        assert!(dmg.load_state(b"not a state").is_err());
+
+        assert_eq!(dmg.save_state(), current);
+    }
+
+    #[test]
+    fn rewind() {
+        let mut dmg = test_dmg();
+        dmg.enable_rewind(10);
+
+        let mut frames = Vec::new();
+        for _ in 0..20 {
+            dmg.run_until_next_frame().unwrap();
+            frames.push(work_ram(&dmg));
+        }
+
+        assert_eq!(dmg.rewind_frames(3), 3);
+        assert_eq!(work_ram(&dmg), frames[16]);
+        assert_eq!(dmg.rewind_frames(20), 7);
+        assert_eq!(work_ram(&dmg), frames[9]);
+        assert_eq!(dmg.rewind_frames(1), 0);
+
+        dmg.run_until_next_frame().unwrap();
+        assert_eq!(work_ram(&dmg), frames[10]);
+
+        // Loading a state drops the history before it
+        let state = dmg.save_state();
+        dmg.run_until_next_frame().unwrap();
+        dmg.load_state(&state).unwrap();
+        assert_eq!(dmg.rewind_frames(1), 0);
+    }
+
+    #[test]
+    fn illegal_opcode_locks() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100] = 0xD3;
+        let mut dmg = Dmg::new(Cart::create_from_slice(&rom));
+        dmg.cpu.set_pc(0x100);
+        dmg.cpu.mem.write(0xff50, 1);
+
+        assert_eq!(dmg.step(), Err(EmulationError::IllegalOpcode { opcode: 0xD3, address: 0x100 }));
+        assert!(dmg.cpu.is_locked());
+
+        // The rest of the system keeps running
+        dmg.run_until_next_frame().unwrap();
+        assert_eq!(dmg.cpu.get_pc(), 0x100);
+    }
+
+    // $0100: LD HL,$C000; CALL $0150; JR $0103
+    // $0150: INC A; LD (HL),A; RET
+    fn debug_dmg() -> Dmg {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x108].copy_from_slice(&[0x21, 0x00, 0xC0, 0xCD, 0x50, 0x01, 0x18, 0xFB]);
+        rom[0x150..0x153].copy_from_slice(&[0x3C, 0x77, 0xC9]);
+        let mut dmg = Dmg::new(Cart::create_from_slice(&rom));
+        dmg.cpu.set_pc(0x100);
+        dmg.cpu.regs_mut().sp = 0xD000;
+        dmg.cpu.mem.write(0xff50, 1);
+        dmg
+    }
+
+    fn run_until_stop(dmg: &mut Dmg) -> StopReason {
+        loop {
+            match dmg.run_until_break().unwrap() {
+                StopReason::FrameReady => (),
+                reason => return reason,
+            }
+        }
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut dmg = debug_dmg();
+        dmg.add_breakpoint(Breakpoint::new(0x150));
+
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Breakpoint(0x150));
+        assert_eq!(dmg.cpu.regs().a, 0);
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Breakpoint(0x150));
+        assert_eq!(dmg.cpu.regs().a, 1);
+
+        assert!(dmg.remove_breakpoint(0x150));
+        dmg.add_breakpoint(Breakpoint {
+            address: 0x151,
+            bank: None,
+            condition: Some(Condition { register: Register::A, comparison: Comparison::Equal, value: 5 }),
+        });
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Breakpoint(0x151));
+        assert_eq!(dmg.cpu.regs().a, 5);
+    }
+
+    #[test]
+    fn banked_breakpoints() {
+        // $0100: CALL $4000; LD A,2; LD ($2000),A; CALL $4000; JR $
+        // $4000 in banks 1 and 2: RET
+        let mut rom = vec![0; 0x10000];
+        rom[0x100..0x10c].copy_from_slice(&[0xCD, 0x00, 0x40, 0x3E, 0x02, 0xEA, 0x00, 0x20, 0xCD, 0x00, 0x40, 0x18]);
+        rom[0x10c] = 0xFE;
+        rom[0x147] = 0x01;
+        rom[0x148] = 0x01;
+        rom[0x4000] = 0xC9;
+        rom[0x8000] = 0xC9;
+        let mut dmg = Dmg::new(Cart::create_from_slice(&rom));
+        dmg.cpu.set_pc(0x100);
+        dmg.cpu.regs_mut().sp = 0xD000;
+        dmg.cpu.mem.write(0xff50, 1);
+
+        dmg.add_breakpoint(Breakpoint { address: 0x4000, bank: Some(2), condition: None });
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Breakpoint(0x4000));
+        assert_eq!(dmg.cpu.mem.cart.rom_bank(), 2);
+        assert_eq!(dmg.cpu.regs().a, 2);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut dmg = debug_dmg();
+        dmg.add_watchpoint(Watchpoint { start: 0xC000, end: 0xC0FF, kind: WatchKind::Write });
+
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Watchpoint { address: 0xC000, value: 1, write: true });
+        assert_eq!(dmg.cpu.get_pc(), 0x152);
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Watchpoint { address: 0xC000, value: 2, write: true });
+
+        // Accesses made outside of run_until_break() are not reported
+        dmg.step().unwrap();
+        while dmg.cpu.get_pc() != 0x152 {
+            dmg.step().unwrap();
+        }
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Watchpoint { address: 0xC000, value: 4, write: true });
+    }
+
+    #[test]
+    fn resume_in_halt() {
+        // HALT; INC A; JR -4, woken up by the timer with IME=0
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x76, 0x3C, 0x18, 0xFC]);
+        let mut dmg = Dmg::new(Cart::create_from_slice(&rom));
+        dmg.cpu.set_pc(0x100);
+        dmg.cpu.mem.write(0xff50, 1);
+        dmg.cpu.mem.write(0xff07, 0x05);
+        dmg.cpu.mem.write(0xffff, 0x04);
+        dmg.add_breakpoint(Breakpoint::new(0x101));
+
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Breakpoint(0x101));
+        assert_eq!(dmg.cpu.regs().a, 0);
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Breakpoint(0x101));
+        assert_eq!(dmg.cpu.regs().a, 1);
+    }
+
+    #[test]
+    fn stepping() {
+        let mut dmg = debug_dmg();
+        dmg.add_breakpoint(Breakpoint::new(0x103));
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Breakpoint(0x103));
+
+        dmg.debug_step(Step::Over);
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Step);
+        assert_eq!(dmg.cpu.get_pc(), 0x106);
+        assert_eq!(dmg.cpu.regs().a, 1);
+
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Breakpoint(0x103));
+        dmg.debug_step(Step::Into);
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Step);
+        assert_eq!(dmg.cpu.get_pc(), 0x150);
+
+        dmg.debug_step(Step::Out);
+        assert_eq!(run_until_stop(&mut dmg), StopReason::Step);
+        assert_eq!(dmg.cpu.get_pc(), 0x106);
+        assert_eq!(dmg.cpu.regs().a, 2);
+    }
+
+    // Log buffer shared with the test, the tracer owns its writer
+    #[derive(Clone, Default)]
+    struct SharedLog(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for SharedLog {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(data)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+
+    #[test]
+    fn trace_log() {
+        let mut dmg = debug_dmg();
+        let log = SharedLog::default();
+        dmg.set_tracer(Some(Tracer::new(log.clone()).with_cycles(true)));
+        dmg.cpu.cycle = 0;
+
+        for _ in 0..3 {
+            dmg.step().unwrap();
+        }
+        dmg.set_tracer(None).unwrap().finish().unwrap();
+        dmg.step().unwrap();
+
+        let log = String::from_utf8(log.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = log.lines().collect();
+        assert_eq!(lines, [
+            "A:00 F:00 B:00 C:00 D:00 E:00 H:00 L:00 SP:D000 PC:0100 PCMEM:21,00,C0,CD CY:0",
+            "A:00 F:00 B:00 C:00 D:00 E:00 H:C0 L:00 SP:D000 PC:0103 PCMEM:CD,50,01,18 CY:12",
+            "A:00 F:00 B:00 C:00 D:00 E:00 H:C0 L:00 SP:CFFE PC:0150 PCMEM:3C,77,C9,00 CY:36",
+        ]);
+    }
+
+    #[test]
+    fn profile() {
+        let mut dmg = debug_dmg();
+        dmg.set_profiler(Some(Profiler::new()));
+
+        // LD HL, then 3 times CALL, INC A, LD (HL), A, RET and JR
+        for _ in 0..16 {
+            dmg.step().unwrap();
+        }
+        let profiler = dmg.set_profiler(None).unwrap();
+
+        assert_eq!(profiler.total(), Counts { instructions: 16, cycles: 204 });
+        assert_eq!(profiler.addresses()[&(0, 0x103)], Counts { instructions: 3, cycles: 72 });
+        assert_eq!(profiler.addresses()[&(0, 0x152)], Counts { instructions: 3, cycles: 48 });
+        assert_eq!(profiler.calls()[&(0, 0x150)], 3);
+
+        assert_eq!(profiler.collapsed_stacks(None), "root 120\nroot;00:0150 84\n");
+        let symbols = Symbols::parse("00:0100 Start\n00:0150 Function\n").unwrap();
+        assert_eq!(profiler.collapsed_stacks(Some(&symbols)), "root 120\nroot;Function 84\n");
+        assert!(profiler.report(Some(&symbols)).contains("84  41.18%           84  41.18%        3  Function"));
+    }
+
+    #[test]
+    fn coverage() {
+        // LD A, $0A; LD ($0000), A; loop: LD A, ($0200); LD ($A000), A; JR loop
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        rom[0x100..0x108].copy_from_slice(&[0x3E, 0x0A, 0xEA, 0x00, 0x00, 0xFA, 0x00, 0x02]);
+        rom[0x108..0x10d].copy_from_slice(&[0xEA, 0x00, 0xA0, 0x18, 0xF8]);
+        let mut dmg = Dmg::new(Cart::create_from_slice(&rom));
+        dmg.cpu.set_pc(0x100);
+        dmg.cpu.mem.write(0xff50, 1);
+        dmg.enable_coverage();
+
+        for _ in 0..6 {
+            dmg.step().unwrap();
+        }
+        let coverage = dmg.disable_coverage().unwrap();
+
+        assert!(coverage.rom()[0x100..0x10d].iter().all(|&flag| flag == EXEC));
+        assert_eq!(coverage.rom()[0x200], READ);
+        assert_eq!(coverage.rom()[0x0000], 0);
+        assert_eq!(coverage.ram()[0x0000], WRITE);
+        assert_eq!(coverage.rom_banks()[0], BankSummary {
+            bank: 0, size: 0x4000, executed: 13, read: 1, written: 0, unused: 0x4000 - 14,
+        });
+        assert_eq!(coverage.rom_banks()[1].unused, 0x4000);
+    }
+}
@@ -0,0 +1,29 @@
+// Errors raised while running the emulation
+//
+// These are conditions the emulator can not (or real hardware does not)
+// handle, they are reported to the embedding application by `Dmg::step()`
+// instead of aborting the process.
+
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EmulationError {
+    /// One of the 11 illegal opcodes was executed, the CPU is locked and
+    /// only a reset resumes execution (like on real hardware)
+    IllegalOpcode { opcode: u8, address: u16 },
+    /// The cart memory bank controller is not emulated
+    UnsupportedMapper(&'static str),
+}
+
+impl fmt::Display for EmulationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EmulationError::IllegalOpcode { opcode, address } => {
+                write!(f, "Illegal instruction op: 0x{:02x} at addr 0x{:04x}, CPU locked", opcode, address)
+            },
+            EmulationError::UnsupportedMapper(mapper) => write!(f, "Cart mapper type not supported: {}", mapper),
+        }
+    }
+}
+
+impl std::error::Error for EmulationError {}
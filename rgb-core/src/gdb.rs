@@ -0,0 +1,382 @@
+// GDB remote serial protocol server
+//
+// Lets a GDB client (or any RSP client) control the emulator over TCP on
+// localhost. The server runs in the calling thread: while a client is
+// attached the emulation only runs on its continue and step commands.
+//
+// Registers are exposed as six 16 bit little-endian registers, in order AF,
+// BC, DE, HL, SP and PC. Memory is accessed through the Gameboy memory map.
+// They are described to the client by the `target.xml` target description,
+// read with qXfer:features:read. Software and hardware breakpoints (Z0/Z1)
+// map to the debugger breakpoints, write/read/access watchpoints (Z2/Z3/Z4)
+// to its watchpoints.
+
+use std::io::{self, Read, Write};
+use std::net::{Ipv4Addr, TcpListener, TcpStream};
+
+use crate::bus::Bus;
+use crate::debugger::{Breakpoint, Step, StopReason, WatchKind, Watchpoint};
+use crate::Dmg;
+
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+const INTERRUPT: u8 = 0x03;
+
+const REGISTER_COUNT: usize = 6;
+
+// Largest packet accepted and sent, advertised to the client
+const PACKET_SIZE: usize = 0x1000;
+
+const TARGET_XML: &str = concat!(
+    r#"<?xml version="1.0"?><!DOCTYPE target SYSTEM "gdb-target.dtd">"#,
+    r#"<target version="1.0"><feature name="org.rgb.sm83.cpu">"#,
+    r#"<reg name="af" bitsize="16" type="int"/>"#,
+    r#"<reg name="bc" bitsize="16" type="int"/>"#,
+    r#"<reg name="de" bitsize="16" type="int"/>"#,
+    r#"<reg name="hl" bitsize="16" type="int"/>"#,
+    r#"<reg name="sp" bitsize="16" type="data_ptr"/>"#,
+    r#"<reg name="pc" bitsize="16" type="code_ptr"/>"#,
+    r#"</feature></target>"#,
+);
+
+pub struct GdbServer {
+    listener: TcpListener,
+}
+
+/// How a client session ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionEnd {
+    /// The client detached, the emulation can go on
+    Detached,
+    /// The client asked to kill the program
+    Killed,
+    /// The connection was closed
+    Disconnected,
+}
+
+impl GdbServer {
+    /// Listen on localhost, a free port is picked if `port` is 0
+    pub fn bind(port: u16) -> io::Result<GdbServer> {
+        Ok(GdbServer {
+            listener: TcpListener::bind((Ipv4Addr::LOCALHOST, port))?,
+        })
+    }
+
+    pub fn port(&self) -> io::Result<u16> {
+        Ok(self.listener.local_addr()?.port())
+    }
+
+    /// Wait for a client and serve it until it leaves
+    pub fn serve(&self, dmg: &mut Dmg) -> io::Result<SessionEnd> {
+        let (stream, _) = self.listener.accept()?;
+        stream.set_nodelay(true)?;
+        Session { stream }.run(dmg)
+    }
+}
+
+struct Session {
+    stream: TcpStream,
+}
+
+impl Session {
+    fn run(&mut self, dmg: &mut Dmg) -> io::Result<SessionEnd> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(SessionEnd::Disconnected),
+            };
+
+            // Packets are hex encoded, other bytes are never valid
+            let reply = match packet.as_bytes().first() {
+                _ if !packet.is_ascii() => error_reply(),
+                Some(b'?') => stop_reply(SIGTRAP),
+                Some(b'g') => read_registers(dmg),
+                Some(b'G') => write_registers(dmg, &packet[1..]),
+                Some(b'p') => read_register(dmg, &packet[1..]),
+                Some(b'P') => write_register(dmg, &packet[1..]),
+                Some(b'm') => read_memory(dmg, &packet[1..]),
+                Some(b'M') => write_memory(dmg, &packet[1..]),
+                Some(b'Z') => set_breakpoint(dmg, &packet[1..], true),
+                Some(b'z') => set_breakpoint(dmg, &packet[1..], false),
+                Some(b'c') => self.resume(dmg, None)?,
+                Some(b's') => self.resume(dmg, Some(Step::Into))?,
+                Some(b'H') => String::from("OK"),
+                Some(b'D') => {
+                    self.write_packet("OK")?;
+                    return Ok(SessionEnd::Detached);
+                },
+                Some(b'k') => return Ok(SessionEnd::Killed),
+                _ if packet.starts_with("qSupported") => format!("PacketSize={:x};qXfer:features:read+", PACKET_SIZE),
+                _ if packet.starts_with("qXfer:features:read:") => read_features(&packet["qXfer:features:read:".len()..]),
+                _ if packet == "qAttached" => String::from("1"),
+                _ => String::new(),
+            };
+
+            self.write_packet(&reply)?;
+        }
+    }
+
+    // Run the emulation until it stops or the client interrupts it
+    fn resume(&mut self, dmg: &mut Dmg, step: Option<Step>) -> io::Result<String> {
+        if let Some(step) = step {
+            dmg.debug_step(step);
+        }
+
+        loop {
+            match dmg.run_until_break() {
+                Ok(StopReason::FrameReady) => {
+                    if self.interrupted()? {
+                        return Ok(stop_reply(SIGINT));
+                    }
+                },
+                Ok(StopReason::Watchpoint { address, write, .. }) => {
+                    // The access watchpoints (Z4) are reported as such
+                    let access = dmg.watchpoints().iter().any(|watchpoint| {
+                        watchpoint.kind == WatchKind::ReadWrite && watchpoint.matches(address, write)
+                    });
+                    let kind = match (access, write) {
+                        (true, _) => "awatch",
+                        (false, true) => "watch",
+                        (false, false) => "rwatch",
+                    };
+                    return Ok(format!("T{:02x}{}:{:04x};", SIGTRAP, kind, address));
+                },
+                Ok(_) => return Ok(stop_reply(SIGTRAP)),
+                Err(_) => return Ok(stop_reply(SIGILL)),
+            }
+        }
+    }
+
+    // Check without blocking if the client sent an interrupt
+    fn interrupted(&mut self) -> io::Result<bool> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.stream.read(&mut byte);
+        self.stream.set_nonblocking(false)?;
+
+        match result {
+            Ok(1) => Ok(byte[0] == INTERRUPT),
+            Ok(_) => Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => Ok(false),
+            Err(err) => Err(err),
+        }
+    }
+
+    // Read the next packet and acknowledge it, returns None when disconnected
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            // Skip acknowledges and interrupts received while stopped
+            match self.read_byte()? {
+                Some(b'$') => (),
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+            let expected = std::str::from_utf8(&checksum).ok().and_then(|checksum| u8::from_str_radix(checksum, 16).ok());
+
+            if expected == Some(checksum_of(&data)) {
+                self.stream.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+            self.stream.write_all(b"-")?;
+        }
+    }
+
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+        self.stream.write_all(packet.as_bytes())
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn stop_reply(signal: u8) -> String {
+    format!("S{:02x}", signal)
+}
+
+fn error_reply() -> String {
+    String::from("E01")
+}
+
+fn register_values(dmg: &Dmg) -> [u16; REGISTER_COUNT] {
+    let regs = dmg.cpu.regs();
+    let pair = |high: u8, low: u8| (high as u16)<<8 | low as u16;
+    [pair(regs.a, regs.f), pair(regs.b, regs.c), pair(regs.d, regs.e), pair(regs.h, regs.l), regs.sp, regs.pc]
+}
+
+fn set_register_value(dmg: &mut Dmg, index: usize, value: u16) {
+    let regs = dmg.cpu.regs_mut();
+    let (high, low) = ((value>>8) as u8, value as u8);
+    match index {
+        0 => { regs.a = high; regs.f = low&0xf0; },
+        1 => { regs.b = high; regs.c = low; },
+        2 => { regs.d = high; regs.e = low; },
+        3 => { regs.h = high; regs.l = low; },
+        4 => regs.sp = value,
+        _ => regs.pc = value,
+    }
+}
+
+fn read_registers(dmg: &Dmg) -> String {
+    register_values(dmg).iter().map(|&value| hex16(value)).collect()
+}
+
+fn write_registers(dmg: &mut Dmg, data: &str) -> String {
+    if data.len() != REGISTER_COUNT*4 {
+        return error_reply();
+    }
+    let values: Option<Vec<u16>> = (0..REGISTER_COUNT).map(|index| parse_hex16(&data[index*4..index*4+4])).collect();
+    match values {
+        Some(values) => {
+            for (index, value) in values.into_iter().enumerate() {
+                set_register_value(dmg, index, value);
+            }
+            String::from("OK")
+        },
+        None => error_reply(),
+    }
+}
+
+fn read_register(dmg: &Dmg, data: &str) -> String {
+    match usize::from_str_radix(data, 16) {
+        Ok(index) if index < REGISTER_COUNT => hex16(register_values(dmg)[index]),
+        _ => error_reply(),
+    }
+}
+
+fn write_register(dmg: &mut Dmg, data: &str) -> String {
+    let parsed = data.split_once('=').and_then(|(index, value)| {
+        Some((usize::from_str_radix(index, 16).ok()?, parse_hex16(value)?))
+    });
+    match parsed {
+        Some((index, value)) if index < REGISTER_COUNT => {
+            set_register_value(dmg, index, value);
+            String::from("OK")
+        },
+        _ => error_reply(),
+    }
+}
+
+// The reply is cut to the bytes fitting in a packet, the client asks for the
+// rest with another packet
+fn read_memory(dmg: &Dmg, data: &str) -> String {
+    match parse_address_length(data) {
+        Some((address, length)) => (0..length.min(PACKET_SIZE/2)).map(|offset| {
+            format!("{:02x}", dmg.cpu.mem.peek(address.wrapping_add(offset as u16)))
+        }).collect(),
+        None => error_reply(),
+    }
+}
+
+fn write_memory(dmg: &mut Dmg, data: &str) -> String {
+    let Some((header, bytes)) = data.split_once(':') else { return error_reply() };
+    let Some((address, length)) = parse_address_length(header) else { return error_reply() };
+    if bytes.len() != length*2 {
+        return error_reply();
+    }
+
+    let values: Option<Vec<u8>> = (0..length).map(|index| u8::from_str_radix(&bytes[index*2..index*2+2], 16).ok()).collect();
+    match values {
+        Some(values) => {
+            for (offset, value) in values.into_iter().enumerate() {
+                dmg.cpu.mem.write(address.wrapping_add(offset as u16), value);
+            }
+            String::from("OK")
+        },
+        None => error_reply(),
+    }
+}
+
+// qXfer:features:read packets: <annex>:<offset>,<length>
+fn read_features(data: &str) -> String {
+    let Some((annex, range)) = data.split_once(':') else { return error_reply() };
+    if annex != "target.xml" {
+        return String::from("E00");
+    }
+    let Some((offset, length)) = range.split_once(',').and_then(|(offset, length)| {
+        Some((usize::from_str_radix(offset, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+    }) else {
+        return error_reply();
+    };
+
+    // 'm' when more data follows, 'l' for the last chunk
+    let start = offset.min(TARGET_XML.len());
+    let end = start.saturating_add(length).min(TARGET_XML.len());
+    let marker = if end < TARGET_XML.len() { 'm' } else { 'l' };
+    format!("{}{}", marker, &TARGET_XML[start..end])
+}
+
+// Z/z packets: <type>,<address>,<kind>
+fn set_breakpoint(dmg: &mut Dmg, data: &str, insert: bool) -> String {
+    let fields: Vec<&str> = data.split(',').collect();
+    let (Some(kind), Some(address)) = (fields.first(), fields.get(1).and_then(|address| u16::from_str_radix(address, 16).ok())) else {
+        return error_reply();
+    };
+    let length = fields.get(2).and_then(|length| u16::from_str_radix(length, 16).ok()).unwrap_or(1).max(1);
+
+    let watch_kind = match *kind {
+        "0" | "1" => {
+            if insert {
+                dmg.add_breakpoint(Breakpoint::new(address));
+            } else {
+                dmg.remove_breakpoint(address);
+            }
+            return String::from("OK");
+        },
+        "2" => WatchKind::Write,
+        "3" => WatchKind::Read,
+        "4" => WatchKind::ReadWrite,
+        _ => return String::new(),
+    };
+
+    let watchpoint = Watchpoint { start: address, end: address.wrapping_add(length - 1), kind: watch_kind };
+    if insert {
+        dmg.add_watchpoint(watchpoint);
+    } else if let Some(index) = dmg.watchpoints().iter().position(|&existing| existing == watchpoint) {
+        dmg.remove_watchpoint(index);
+    }
+    String::from("OK")
+}
+
+fn hex16(value: u16) -> String {
+    format!("{:02x}{:02x}", value as u8, (value>>8) as u8)
+}
+
+// Parse a 16 bit register value, sent little-endian
+fn parse_hex16(data: &str) -> Option<u16> {
+    if data.len() == 4 {
+        let low = u8::from_str_radix(&data[0..2], 16).ok()?;
+        let high = u8::from_str_radix(&data[2..4], 16).ok()?;
+        Some((high as u16)<<8 | low as u16)
+    } else {
+        None
+    }
+}
+
+fn parse_address_length(data: &str) -> Option<(u16, usize)> {
+    let (address, length) = data.split_once(',')?;
+    Some((u16::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
@@ -1,3 +1,4 @@
+use crate::savestate::{StateLoadError, StateReader, StateWriter};
 
 // bit position in state variables
 const STATE_RIGH: u8 = 1;
@@ -60,6 +61,17 @@ impl Joypad {
         }
     }
 
+    // The buttons state comes from the host and is not part of the save state,
+    // this avoids buttons being stuck after a load.
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.reg);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        self.reg = state.read_u8()?;
+        Ok(())
+    }
+
     pub fn step(&mut self) {
         let mut newreg = 0x0f;
 
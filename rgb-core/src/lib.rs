@@ -1,6 +1,7 @@
 #![warn(clippy::all)]
 #![allow(clippy::upper_case_acronyms)]
 
+pub mod bus;
 pub mod cart;
 pub mod cpu;
 pub mod mem;
@@ -9,7 +10,22 @@ pub mod bootstrap;
 pub mod joypad;
 pub mod timer;
 pub mod audio;
+pub mod serial;
+pub mod savestate;
+pub mod rewind;
+pub mod error;
+pub mod disasm;
+pub mod debugger;
+#[cfg(feature="gdb")]
+pub mod gdb;
+pub mod trace;
+pub mod symbols;
+pub mod profile;
+pub mod coverage;
+pub mod callstack;
+pub mod rtc;
 
 mod dmg;
+mod scheduler;
 
 pub use dmg::Dmg;
\ No newline at end of file
@@ -1,12 +1,18 @@
 // Implements the memory multiplexer, the fast ram and the work ram.
 // This is a gameboy for now, not a gameboy color, so no banking of the work ram
 
+use crate::bus::Bus;
+use crate::coverage::{self, Coverage};
+use crate::debugger::{StopReason, Watchpoint};
 use crate::cart::Cart;
 use crate::video::Video;
 use crate::bootstrap::Bootstrap;
 use crate::joypad::Joypad;
 use crate::timer::Timer;
 use crate::audio::Audio;
+use crate::serial::Serial;
+use crate::scheduler::{Event, Scheduler};
+use crate::savestate::{StateLoadError, StateReader, StateWriter};
 
 pub struct Mem {
     bootstrap: Bootstrap,
@@ -21,13 +27,28 @@ pub struct Mem {
     pub joypad: Joypad,
     pub timer: Timer,
     pub audio: Audio,
+    pub serial: Serial,
 
     oam_dma_source: Option<u16>,
+
+    /// Clock cycles ticked by the CPU, the peripherals are run up to it
+    cycle: usize,
+    /// Next events of the timer, video, audio and cart clock
+    scheduler: Scheduler,
+    /// A video frame was completed since the flag was last taken
+    pub(crate) frame_ready: bool,
+
+    /// Debugger watchpoints, checked on the CPU accesses
+    pub(crate) watchpoints: Vec<Watchpoint>,
+    /// First watchpoint hit since the flag was last taken
+    pub(crate) watchpoint_hit: Option<StopReason>,
+    /// Records the cart accesses when enabled
+    pub(crate) coverage: Option<Coverage>,
 }
 
 impl Mem {
     pub fn new(bootstrap: Bootstrap, cart: Cart) -> Mem {
-        Mem {
+        let mut mem = Mem {
             bootstrap,
             cart,
             work: vec![0; 8*1024],
@@ -39,9 +60,20 @@ impl Mem {
             joypad: Joypad::new(),
             timer: Timer::new(),
             audio: Audio::new(),
+            serial: Serial::new(),
 
             oam_dma_source: None,
-        }
+
+            cycle: 0,
+            scheduler: Scheduler::new(),
+            frame_ready: false,
+
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
+            coverage: None,
+        };
+        mem.schedule_all();
+        mem
     }
 
     pub fn read(&self, address: u16) -> u8 {
@@ -55,12 +87,13 @@ impl Mem {
             _ if address < 0xFF00 => 0, // Not usable, ignored
             _ if address < 0xFF80 => match address {
                 0xFF00 => self.joypad.read(address),
+                0xFF01 | 0xFF02 => self.serial.read(address),
                 0xFF0F => self.reg_if,
-                _ if address >= 0xff10 && address < 0xFF27 => self.audio.read(address),
+                _ if (0xff10..0xFF27).contains(&address) => self.audio.read(address),
                 0xff50 => self.page0_mode,
                 0xff46 => 0,
                 0xff4d => 0xff,
-                _ if address & 0x00fc == 0x04 => self.timer.read(address),
+                _ if address & 0x00fc == 0x04 => self.timer.at(self.cycle).read(address),
                 _ if address & 0x00f0 == 0x40 => self.video.read(address),
                 _ => 0xff,
             }, // IO registers
@@ -72,20 +105,28 @@ impl Mem {
 
     pub fn write(&mut self, address: u16, data: u8) {
         match address {
-            _ if address < 0x8000 => self.cart.write(address, data), // Cart ROM
+            _ if address < 0x8000 => self.write_cart(address, data), // Cart ROM
             _ if address < 0xA000 => self.video.write(address, data), // VRAM
-            _ if address < 0xC000 => self.cart.write(address, data), // Cart RAM
+            _ if address < 0xC000 => self.write_cart(address, data), // Cart RAM
             _ if address < 0xFE00 => self.work[(address&0x1FFF) as usize] = data,
             _ if address < 0xFEA0 => self.video.write(address, data), // OAM
             _ if address < 0xFF00 => (), // Not usable, ignored
             _ if address < 0xFF80 => match address {
                 0xFF00 => self.joypad.write(address, data),
-                0xFF01 => { print!("\x1b[1;34m{}\x1b[0m", (data as char).to_string()); /*self.reg_if |= 0x08;*/ },
+                0xFF01 | 0xFF02 => self.reg_if |= self.serial.write(address, data),
                 0xFF0F => self.reg_if = data,
-                _ if address >= 0xff10 && address < 0xFF40 => self.audio.write(address, data),
+                _ if (0xff10..0xFF40).contains(&address) => {
+                    self.audio.step(self.cycle);
+                    self.audio.write(address, data);
+                    self.scheduler.schedule(Event::Audio, self.audio.next_event());
+                },
                 0xff46 => {self.oam_dma_source = Some((data as u16)<<8)},
                 0xff50 if self.page0_mode == 0 => self.page0_mode = data,
-                _ if address & 0x00fc == 0x04 => self.timer.write(address, data),
+                _ if address & 0x00fc == 0x04 => {
+                    self.reg_if |= self.timer.step(self.cycle);
+                    self.timer.write(address, data);
+                    self.scheduler.schedule(Event::Timer, self.timer.next_event());
+                },
                 _ if address & 0x00f0 == 0x40 => self.video.write(address, data),
                 _ => (),
             }, // IO registers
@@ -95,10 +136,100 @@ impl Mem {
         }
     }
 
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.work);
+        state.write_bytes(&self.hram);
+        state.write_u8(self.page0_mode);
+        state.write_u8(self.reg_ie);
+        state.write_u8(self.reg_if);
+        state.write_bool(self.oam_dma_source.is_some());
+        state.write_u16(self.oam_dma_source.unwrap_or(0));
+        state.write_usize(self.cycle);
+
+        self.cart.save_state(state);
+        self.video.save_state(state);
+        self.joypad.save_state(state);
+        self.timer.save_state(state);
+        self.audio.save_state(state);
+        self.serial.save_state(state);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        state.read_bytes_into(&mut self.work)?;
+        state.read_bytes_into(&mut self.hram)?;
+        self.page0_mode = state.read_u8()?;
+        self.reg_ie = state.read_u8()?;
+        self.reg_if = state.read_u8()?;
+        let dma_pending = state.read_bool()?;
+        let dma_source = state.read_u16()?;
+        self.oam_dma_source = if dma_pending { Some(dma_source) } else { None };
+        self.cycle = state.read_usize()?;
+
+        self.cart.load_state(state)?;
+        self.video.load_state(state)?;
+        self.joypad.load_state(state)?;
+        self.timer.load_state(state)?;
+        self.audio.load_state(state)?;
+        self.serial.load_state(state)?;
+        self.schedule_all();
+        Ok(())
+    }
+
+    // The cart clock is run up to the write, which can change its registers
+    fn write_cart(&mut self, address: u16, data: u8) {
+        self.cart.step(self.cycle);
+        self.cart.write(address, data);
+        self.scheduler.schedule(Event::Cart, self.cart.next_event());
+    }
+
+    fn schedule_all(&mut self) {
+        self.scheduler.schedule(Event::Timer, self.timer.next_event());
+        self.scheduler.schedule(Event::Video, self.video.next_event());
+        self.scheduler.schedule(Event::Audio, self.audio.next_event());
+        self.scheduler.schedule(Event::Cart, self.cart.next_event());
+    }
+
+    // Run the audio generators up to the current cycle
+    fn step_audio(&mut self) {
+        self.audio.step(self.cycle);
+        self.scheduler.schedule(Event::Audio, self.audio.next_event());
+    }
+
+    // Run the peripherals whose next event is due
+    fn run_events(&mut self) {
+        while let Some(event) = self.scheduler.pop(self.cycle) {
+            match event {
+                Event::Timer => {
+                    self.reg_if |= self.timer.step(self.cycle);
+                    self.scheduler.schedule(Event::Timer, self.timer.next_event());
+                },
+                Event::Video => {
+                    self.reg_if |= self.video.step(self.cycle);
+                    self.frame_ready |= self.video.image_ready;
+                    self.scheduler.schedule(Event::Video, self.video.next_event());
+                },
+                Event::Audio => self.step_audio(),
+                Event::Cart => {
+                    self.cart.step(self.cycle);
+                    self.scheduler.schedule(Event::Cart, self.cart.next_event());
+                },
+            }
+        }
+    }
+
+    fn check_watchpoints(&mut self, address: u16, value: u8, write: bool) {
+        if self.watchpoint_hit.is_none() && self.watchpoints.iter().any(|watchpoint| watchpoint.matches(address, write)) {
+            self.watchpoint_hit = Some(StopReason::Watchpoint { address, value, write });
+        }
+    }
+
     pub fn step(&mut self) {
         if let Some(oam_dma_source) = self.oam_dma_source {
             for i in 0u16..0xA0 {
                 let data = self.read(oam_dma_source + i);
+                if let Some(coverage) = &mut self.coverage {
+                    coverage.record(&self.cart, oam_dma_source + i, coverage::READ);
+                }
                 self.write(0xFE00 + i, data);
             }
 
@@ -106,3 +237,60 @@ impl Mem {
         }
     }
 }
+
+impl Bus for Mem {
+    fn read(&mut self, address: u16) -> u8 {
+        // The audio registers are read once the generators caught up
+        if (0xff10..0xFF27).contains(&address) {
+            self.step_audio();
+        }
+        let value = Mem::read(self, address);
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, value, false);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            // The bootstrap ROM hides the beginning of the cart
+            if address >= 0x0100 || self.page0_mode != 0 {
+                coverage.record_read(&self.cart, address);
+            }
+        }
+        value
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, data, true);
+        }
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record_write(&self.cart, address);
+        }
+        Mem::write(self, address, data)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        Mem::read(self, address)
+    }
+
+    // Run the peripherals, memory accesses of the CPU then see their state at
+    // the exact M-cycle they happen. The timer, video, audio and cart clock
+    // are only run at their scheduled events, and caught up when their
+    // registers are accessed.
+    fn tick(&mut self, cycles: usize) {
+        self.cycle += cycles;
+
+        self.step();
+        if self.scheduler.next() <= self.cycle {
+            self.run_events();
+        }
+        self.reg_if |= self.video.lyc_interrupt();
+        self.joypad.step();
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.reg_ie & self.reg_if
+    }
+
+    fn acknowledge_interrupt(&mut self, interrupt: u8) {
+        self.reg_if &= !interrupt;
+    }
+}
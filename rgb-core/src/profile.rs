@@ -0,0 +1,260 @@
+// Execution profiler
+//
+// Accumulates, for every step of the CPU, the executed instructions and clock
+// cycles per (bank, PC), and the cycles per call stack. The call stack is the
+// shadow call stack of the CPU (see callstack.rs), made of the taken CALL and
+// RST instructions and the interrupt dispatches.
+//
+// The cycles spent halted or stopped are accounted to the HALT/STOP
+// instruction, and the interrupt dispatch cycles to the interrupt handler.
+//
+// Two reports are produced: a flat text report of the hottest addresses and
+// functions, and a collapsed stack file, one `root;caller;callee cycles` line
+// per stack, which can be turned into a flame graph by flamegraph.pl or
+// inferno. Functions are named by their debug symbol when available, else by
+// their `bank:address`.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+
+use crate::callstack::{CallStack, Frame};
+use crate::cpu::Regs;
+use crate::symbols::Symbols;
+
+/// Number of addresses listed in the flat report
+const REPORT_ADDRESSES: usize = 50;
+
+/// Code location, as `(bank, address)`
+pub type Location = (u16, u16);
+
+/// What the CPU did during a step
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Activity {
+    Instruction,
+    Interrupt,
+    /// Halted, stopped or locked
+    Idle,
+}
+
+/// CPU state at the start of a step
+pub(crate) struct StepStart {
+    location: Location,
+    // Calls recorded by the call stack before the step
+    calls: u64,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counts {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+// Node of the call tree, a function called from a given stack
+struct Node {
+    function: Option<Location>,
+    parent: usize,
+    cycles: u64,
+    children: HashMap<Location, usize>,
+}
+
+pub struct Profiler {
+    addresses: HashMap<Location, Counts>,
+    calls: HashMap<Location, u64>,
+    // Call tree, the root node 0 is the code running outside of any call
+    nodes: Vec<Node>,
+    // Node of the current call stack, and the changes of the call stack it
+    // was found at
+    current: usize,
+    synced: Option<u64>,
+    total: Counts,
+}
+
+impl StepStart {
+    pub(crate) fn new(regs: &Regs, rom_bank: usize, call_stack: &CallStack) -> StepStart {
+        StepStart {
+            location: (bank_of(regs.pc, rom_bank), regs.pc),
+            calls: call_stack.calls(),
+        }
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            addresses: HashMap::new(),
+            calls: HashMap::new(),
+            nodes: vec![Node { function: None, parent: 0, cycles: 0, children: HashMap::new() }],
+            current: 0,
+            synced: None,
+            total: Counts::default(),
+        }
+    }
+
+    /// Account a CPU step of `cycles` clock cycles
+    pub(crate) fn record(&mut self, start: StepStart, activity: Activity, cycles: usize, call_stack: &CallStack) {
+        let cycles = cycles as u64;
+        let instructions = (activity == Activity::Instruction) as u64;
+        self.total.instructions += instructions;
+        self.total.cycles += cycles;
+
+        // The interrupt dispatch is accounted to the handler, the cycles of a
+        // call or a return to the function executing it
+        if activity == Activity::Interrupt {
+            self.sync(call_stack);
+        } else {
+            let counts = self.addresses.entry(start.location).or_default();
+            counts.instructions += instructions;
+            counts.cycles += cycles;
+        }
+        self.nodes[self.current].cycles += cycles;
+
+        let calls = (call_stack.calls() - start.calls) as usize;
+        for frame in call_stack.frames().iter().rev().take(calls) {
+            *self.calls.entry(function_of(frame)).or_default() += 1;
+        }
+        self.sync(call_stack);
+    }
+
+    // Find the node of the current call stack, when it changed
+    fn sync(&mut self, call_stack: &CallStack) {
+        if self.synced == Some(call_stack.changes()) {
+            return;
+        }
+        self.synced = Some(call_stack.changes());
+        self.current = call_stack.frames().iter().fold(0, |parent, frame| self.child(parent, function_of(frame)));
+    }
+
+    fn child(&mut self, parent: usize, function: Location) -> usize {
+        match self.nodes[parent].children.get(&function) {
+            Some(&node) => node,
+            None => {
+                self.nodes.push(Node { function: Some(function), parent, cycles: 0, children: HashMap::new() });
+                let node = self.nodes.len() - 1;
+                self.nodes[parent].children.insert(function, node);
+                node
+            },
+        }
+    }
+
+    /// Instructions and cycles of the whole profile
+    pub fn total(&self) -> Counts {
+        self.total
+    }
+
+    /// Instructions and cycles per address, the cycles include the time
+    /// spent halted at a HALT instruction
+    pub fn addresses(&self) -> &HashMap<Location, Counts> {
+        &self.addresses
+    }
+
+    /// Number of calls of each function, including interrupt handlers
+    pub fn calls(&self) -> &HashMap<Location, u64> {
+        &self.calls
+    }
+
+    /// Flat text report of the hottest addresses and functions
+    pub fn report(&self, symbols: Option<&Symbols>) -> String {
+        let mut report = String::new();
+        let total = self.total.cycles.max(1) as f64;
+        writeln!(report, "Total: {} instructions, {} cycles", self.total.instructions, self.total.cycles).unwrap();
+
+        writeln!(report, "\nAddresses:").unwrap();
+        writeln!(report, "{:>12} {:>7} {:>12}  location", "cycles", "%", "instructions").unwrap();
+        let mut addresses: Vec<_> = self.addresses.iter().collect();
+        addresses.sort_by_key(|&(&location, counts)| (std::cmp::Reverse(counts.cycles), location));
+        for (&location, counts) in addresses.into_iter().take(REPORT_ADDRESSES) {
+            writeln!(report, "{:>12} {:>6.2}% {:>12}  {}", counts.cycles, counts.cycles as f64*100.0/total,
+                     counts.instructions, describe(location, symbols)).unwrap();
+        }
+
+        writeln!(report, "\nFunctions:").unwrap();
+        writeln!(report, "{:>12} {:>7} {:>12} {:>7} {:>8}  function", "inclusive", "%", "exclusive", "%", "calls").unwrap();
+        let functions = self.functions();
+        let mut functions: Vec<_> = functions.iter().collect();
+        functions.sort_by_key(|&(&location, &(inclusive, _))| (std::cmp::Reverse(inclusive), location));
+        for (&location, &(inclusive, exclusive)) in functions {
+            let calls = self.calls.get(&location).copied().unwrap_or(0);
+            writeln!(report, "{:>12} {:>6.2}% {:>12} {:>6.2}% {:>8}  {}", inclusive, inclusive as f64*100.0/total,
+                     exclusive, exclusive as f64*100.0/total, calls, describe(location, symbols)).unwrap();
+        }
+
+        report
+    }
+
+    /// Collapsed stacks, one line per call stack with its exclusive cycles
+    pub fn collapsed_stacks(&self, symbols: Option<&Symbols>) -> String {
+        let mut lines = Vec::new();
+        for (index, node) in self.nodes.iter().enumerate() {
+            if node.cycles == 0 {
+                continue;
+            }
+            let mut names = Vec::new();
+            let mut current = index;
+            while current != 0 {
+                names.push(self.nodes[current].function.map(|function| describe(function, symbols)).unwrap_or_default());
+                current = self.nodes[current].parent;
+            }
+            names.push(String::from("root"));
+            names.reverse();
+            lines.push(format!("{} {}", names.join(";"), node.cycles));
+        }
+        lines.sort();
+
+        let mut stacks = lines.join("\n");
+        stacks.push('\n');
+        stacks
+    }
+
+    // Inclusive and exclusive cycles per function, recursive calls are only
+    // counted once in the inclusive cycles
+    fn functions(&self) -> HashMap<Location, (u64, u64)> {
+        let mut subtree: Vec<u64> = self.nodes.iter().map(|node| node.cycles).collect();
+        // Children are always created after their parent
+        for index in (1..self.nodes.len()).rev() {
+            subtree[self.nodes[index].parent] += subtree[index];
+        }
+
+        let mut functions: HashMap<Location, (u64, u64)> = HashMap::new();
+        for (index, node) in self.nodes.iter().enumerate().skip(1) {
+            let Some(function) = node.function else { continue };
+            let entry = functions.entry(function).or_default();
+            entry.1 += node.cycles;
+            if !self.has_ancestor(index, function) {
+                entry.0 += subtree[index];
+            }
+        }
+        functions
+    }
+
+    fn has_ancestor(&self, mut index: usize, function: Location) -> bool {
+        while index != 0 {
+            index = self.nodes[index].parent;
+            if self.nodes[index].function == Some(function) {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+fn bank_of(address: u16, rom_bank: usize) -> u16 {
+    if (0x4000..0x8000).contains(&address) { rom_bank as u16 } else { 0 }
+}
+
+fn function_of(frame: &Frame) -> Location {
+    (bank_of(frame.target, frame.rom_bank), frame.target)
+}
+
+fn describe(location: Location, symbols: Option<&Symbols>) -> String {
+    let (bank, address) = location;
+    match symbols.and_then(|symbols| symbols.lookup(address, bank as usize)) {
+        Some(label) => label.to_string(),
+        None => format!("{:02x}:{:04x}", bank, address),
+    }
+}
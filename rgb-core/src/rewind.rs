@@ -0,0 +1,188 @@
+// Rewind buffer
+//
+// Only the latest snapshot is kept in full. Each older snapshot is stored as
+// the XOR of itself with the snapshot that follows it, which is mostly zeros
+// since consecutive frames differ very little. The zero runs are then
+// run-length encoded:
+//   [zero run length][literal length][literal bytes] ...
+// with the lengths encoded as LEB128 varints.
+
+use std::collections::VecDeque;
+
+pub struct Rewind {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    /// Create a rewind buffer able to go back `capacity` snapshots
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity,
+            latest: None,
+            deltas: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Record a new snapshot, the oldest one is dropped if the buffer is full
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(latest) = self.latest.take() {
+            if self.capacity == 0 {
+                self.latest = Some(snapshot);
+                return;
+            }
+            if self.deltas.len() == self.capacity {
+                self.deltas.pop_front();
+            }
+            self.deltas.push_back(encode_delta(&snapshot, &latest));
+        }
+        self.latest = Some(snapshot);
+    }
+
+    /// Go back up to `count` snapshots
+    ///
+    /// Returns the number of snapshots actually rewound and the snapshot we
+    /// are now at, or `None` if no snapshot has been recorded.
+    pub fn rewind(&mut self, count: usize) -> Option<(usize, &[u8])> {
+        let mut latest = self.latest.take()?;
+        let mut rewound = 0;
+        while rewound < count {
+            match self.deltas.pop_back() {
+                Some(delta) => latest = decode_delta(&latest, &delta),
+                None => break,
+            }
+            rewound += 1;
+        }
+        self.latest = Some(latest);
+        self.latest.as_deref().map(|latest| (rewound, latest))
+    }
+
+    /// Number of snapshots that can be rewound
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    /// Memory used by the stored deltas, in bytes
+    pub fn delta_size(&self) -> usize {
+        self.deltas.iter().map(|delta| delta.len()).sum()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+}
+
+// Encode the difference needed to go back from `current` to `previous`
+fn encode_delta(current: &[u8], previous: &[u8]) -> Vec<u8> {
+    let length = current.len().max(previous.len());
+    let xor = |i: usize| current.get(i).unwrap_or(&0) ^ previous.get(i).unwrap_or(&0);
+
+    let mut delta = Vec::new();
+    write_varint(&mut delta, previous.len());
+
+    let mut i = 0;
+    while i < length {
+        let zeros_start = i;
+        while i < length && xor(i) == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        while i < length && xor(i) != 0 {
+            i += 1;
+        }
+        write_varint(&mut delta, literal_start - zeros_start);
+        write_varint(&mut delta, i - literal_start);
+        delta.extend((literal_start..i).map(xor));
+    }
+
+    delta
+}
+
+fn decode_delta(current: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_varint(delta, &mut position);
+
+    let mut previous = current.to_vec();
+    previous.resize(length.max(current.len()), 0);
+
+    let mut i = 0;
+    while position < delta.len() {
+        i += read_varint(delta, &mut position);
+        let literal_length = read_varint(delta, &mut position);
+        for (byte, diff) in previous[i..i+literal_length].iter_mut().zip(&delta[position..]) {
+            *byte ^= diff;
+        }
+        i += literal_length;
+        position += literal_length;
+    }
+
+    previous.truncate(length);
+    previous
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buffer.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn read_varint(buffer: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = buffer[*position];
+        *position += 1;
+        value |= ((byte & 0x7f) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_delta, encode_delta, Rewind};
+
+    #[test]
+    fn delta_round_trip() {
+        let previous: Vec<u8> = (0..1000).map(|i| (i / 7) as u8).collect();
+        let mut current = previous.clone();
+        current[3] ^= 0x55;
+        current[500..520].iter_mut().for_each(|b| *b = 0xAA);
+        current.truncate(990);
+
+        let delta = encode_delta(&current, &previous);
+        assert!(delta.len() < 64);
+        assert_eq!(decode_delta(&current, &delta), previous);
+        assert_eq!(decode_delta(&previous, &encode_delta(&previous, &current)), current);
+    }
+
+    #[test]
+    fn bounded_rewind() {
+        let mut rewind = Rewind::new(3);
+        assert!(rewind.rewind(1).is_none());
+
+        for i in 0..6u8 {
+            rewind.push(vec![i; 16]);
+        }
+        assert_eq!(rewind.len(), 3);
+
+        let (rewound, snapshot) = rewind.rewind(2).unwrap();
+        assert_eq!(rewound, 2);
+        assert_eq!(snapshot, &[3; 16]);
+
+        let (rewound, snapshot) = rewind.rewind(5).unwrap();
+        assert_eq!(rewound, 1);
+        assert_eq!(snapshot, &[2; 16]);
+        assert!(rewind.is_empty());
+    }
+}
@@ -0,0 +1,327 @@
+// MBC3 real-time clock
+//
+// Counts seconds, minutes, hours and a 9-bit day counter. The halt bit stops
+// the clock, and the carry bit is set when the day counter overflows, until
+// cleared by the game. The game reads a latched copy of the counters, copied
+// when 0 then 1 are written to the latch register.
+//
+// The clock advances with the emulated cycles, one second every 4194304
+// cycles. With the host sync enabled, it also catches up with the host time
+// elapsed since a save state was written when loading it back.
+//
+// Save files carry the clock in the footer appended to the cart RAM by BGB
+// and VBA: the counters then the latched counters as 32-bit little endian
+// words, followed by the UNIX time the file was written at, as a 64-bit word
+// (48 bytes footer) or a 32-bit word (44 bytes footer, older VBA versions).
+// The clock catches up with the time elapsed since when the footer is loaded.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::savestate::{StateLoadError, StateReader, StateWriter};
+use crate::scheduler::NEVER;
+
+/// Clock cycles per RTC second
+pub const CLOCK_RATE: usize = 4_194_304;
+
+/// Length of the save file footer
+pub const FOOTER_LENGTH: usize = 48;
+/// Length of the save file footer with a 32-bit timestamp
+pub const SHORT_FOOTER_LENGTH: usize = 44;
+
+// Register indexes, selected as RAM banks 0x08 to 0x0C
+const SECONDS: usize = 0;
+const MINUTES: usize = 1;
+const HOURS: usize = 2;
+const DAYS_LOW: usize = 3;
+const DAYS_HIGH: usize = 4;
+
+// Bits implemented in each register
+const MASKS: [u8; 5] = [0x3f, 0x3f, 0x1f, 0xff, 0xc1];
+
+const DAY_HIGH: u8 = 0x01;
+const HALT: u8 = 0x40;
+const CARRY: u8 = 0x80;
+
+pub struct Rtc {
+    registers: [u8; 5],
+    latched: [u8; 5],
+    // The last write to the latch register was 0
+    latch_armed: bool,
+
+    // Cycles counted toward the next second, and cycle they were counted to
+    subsecond: usize,
+    prev_cycle: usize,
+
+    host_sync: bool,
+}
+
+impl Rtc {
+    pub fn new() -> Rtc {
+        Rtc {
+            registers: [0; 5],
+            latched: [0; 5],
+            latch_armed: false,
+            subsecond: 0,
+            prev_cycle: 0,
+            host_sync: false,
+        }
+    }
+
+    /// Catch up with the host time elapsed since a save state was written
+    /// when loading it
+    pub fn set_host_sync(&mut self, host_sync: bool) {
+        self.host_sync = host_sync;
+    }
+
+    pub fn halted(&self) -> bool {
+        self.registers[DAYS_HIGH]&HALT != 0
+    }
+
+    /// Counters as (days, hours, minutes, seconds)
+    pub fn time(&self) -> (u16, u8, u8, u8) {
+        let days = ((self.registers[DAYS_HIGH]&DAY_HIGH) as u16) << 8 | self.registers[DAYS_LOW] as u16;
+        (days, self.registers[HOURS], self.registers[MINUTES], self.registers[SECONDS])
+    }
+
+    /// Move the clock forward, unless halted
+    pub fn advance(&mut self, mut seconds: u64) {
+        if self.halted() {
+            return;
+        }
+
+        // Out of range counters count up to their register size before wrapping
+        while seconds > 0 && !self.in_range() {
+            self.tick();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+
+        let (days, hours, minutes, secs) = self.time();
+        let total = seconds + secs as u64 + 60*(minutes as u64 + 60*(hours as u64 + 24*days as u64));
+        let days = total/86400;
+        self.registers[SECONDS] = (total%60) as u8;
+        self.registers[MINUTES] = (total/60%60) as u8;
+        self.registers[HOURS] = (total/3600%24) as u8;
+        self.set_days(days);
+    }
+
+    fn in_range(&self) -> bool {
+        self.registers[SECONDS] < 60 && self.registers[MINUTES] < 60 && self.registers[HOURS] < 24
+    }
+
+    // Count one second
+    fn tick(&mut self) {
+        let (days, hours, minutes, seconds) = self.time();
+        self.registers[SECONDS] = (seconds + 1)&MASKS[SECONDS];
+        if seconds != 59 {
+            return;
+        }
+        self.registers[SECONDS] = 0;
+        self.registers[MINUTES] = (minutes + 1)&MASKS[MINUTES];
+        if minutes != 59 {
+            return;
+        }
+        self.registers[MINUTES] = 0;
+        self.registers[HOURS] = (hours + 1)&MASKS[HOURS];
+        if hours != 23 {
+            return;
+        }
+        self.registers[HOURS] = 0;
+        self.set_days(days as u64 + 1);
+    }
+
+    fn set_days(&mut self, days: u64) {
+        if days > 0x1ff {
+            self.registers[DAYS_HIGH] |= CARRY;
+        }
+        self.registers[DAYS_LOW] = days as u8;
+        self.registers[DAYS_HIGH] = (self.registers[DAYS_HIGH]&!DAY_HIGH) | ((days >> 8) as u8&DAY_HIGH);
+    }
+
+    /// Save file footer, timestamped with the host time
+    pub fn footer(&self) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(FOOTER_LENGTH);
+        for register in self.registers.iter().chain(&self.latched) {
+            footer.extend_from_slice(&(*register as u32).to_le_bytes());
+        }
+        footer.extend_from_slice(&unix_time().to_le_bytes());
+        footer
+    }
+
+    /// Load a save file footer, of either length, and catch up with the
+    /// host time elapsed since it was written
+    pub fn load_footer(&mut self, footer: &[u8]) -> Result<(), String> {
+        if footer.len() != FOOTER_LENGTH && footer.len() != SHORT_FOOTER_LENGTH {
+            return Err(String::from("Invalid RTC footer length."));
+        }
+
+        let word = |index: usize| u32::from_le_bytes(footer[4*index..4*index + 4].try_into().unwrap());
+        for (register, mask) in MASKS.iter().enumerate() {
+            self.registers[register] = word(register) as u8&mask;
+            self.latched[register] = word(register + 5) as u8&mask;
+        }
+        let timestamp = if footer.len() == FOOTER_LENGTH {
+            u64::from_le_bytes(footer[40..48].try_into().unwrap())
+        } else {
+            word(10) as u64
+        };
+
+        self.subsecond = 0;
+        self.advance(unix_time().saturating_sub(timestamp));
+        Ok(())
+    }
+
+    /// Read the latched copy of a register, selected as 0x08 to 0x0C
+    pub(crate) fn read(&self, select: usize) -> u8 {
+        self.latched[select - 0x08] | !MASKS[select - 0x08]
+    }
+
+    /// Write a register, the clock has to be run up to the current cycle
+    pub(crate) fn write(&mut self, select: usize, data: u8) {
+        let register = select - 0x08;
+        self.registers[register] = data&MASKS[register];
+        // Writing the seconds resets the sub-second divider
+        if register == SECONDS {
+            self.subsecond = 0;
+        }
+    }
+
+    pub(crate) fn latch(&mut self, data: u8) {
+        if self.latch_armed && data == 0x01 {
+            self.latched = self.registers;
+        }
+        self.latch_armed = data == 0x00;
+    }
+
+    /// Run the clock up to `cycle`
+    pub(crate) fn step(&mut self, cycle: usize) {
+        let elapsed = cycle.saturating_sub(self.prev_cycle);
+        self.prev_cycle = cycle;
+        if self.halted() {
+            return;
+        }
+
+        self.subsecond += elapsed;
+        let seconds = self.subsecond/CLOCK_RATE;
+        self.subsecond %= CLOCK_RATE;
+        self.advance(seconds as u64);
+    }
+
+    /// Cycle of the next second
+    pub(crate) fn next_event(&self) -> usize {
+        if self.halted() { NEVER } else { self.prev_cycle + CLOCK_RATE - self.subsecond }
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_bytes(&self.registers);
+        state.write_bytes(&self.latched);
+        state.write_bool(self.latch_armed);
+        state.write_usize(self.subsecond);
+        state.write_usize(self.prev_cycle);
+        state.write_u64(if self.host_sync && state.host_time() { unix_time() } else { 0 });
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        state.read_bytes_into(&mut self.registers)?;
+        state.read_bytes_into(&mut self.latched)?;
+        self.latch_armed = state.read_bool()?;
+        self.subsecond = state.read_usize()?;
+        self.prev_cycle = state.read_usize()?;
+        let timestamp = state.read_u64()?;
+        if self.host_sync && state.host_time() && timestamp != 0 {
+            self.advance(unix_time().saturating_sub(timestamp));
+        }
+        Ok(())
+    }
+}
+
+impl Default for Rtc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Host time, in seconds since the UNIX epoch
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |duration| duration.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_and_latches() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x08, 58);
+        rtc.write(0x09, 59);
+        rtc.write(0x0A, 23);
+        rtc.write(0x0B, 0xff);
+        rtc.write(0x0C, 0x01);
+
+        rtc.step(2*CLOCK_RATE - 1);
+        assert_eq!(rtc.time(), (0x1ff, 23, 59, 59));
+        rtc.step(2*CLOCK_RATE);
+        assert_eq!(rtc.time(), (0, 0, 0, 0));
+        assert_eq!(rtc.registers[DAYS_HIGH], CARRY);
+
+        // The game reads the latched registers, nothing was latched yet
+        assert_eq!(rtc.read(0x08), 0xc0);
+        rtc.latch(0x00);
+        rtc.latch(0x01);
+        assert_eq!(rtc.read(0x08), 0xc0);
+        assert_eq!(rtc.read(0x0C), 0x3e | CARRY);
+
+        // Halted, then restarted from a counter out of range
+        rtc.write(0x0C, HALT);
+        rtc.step(10*CLOCK_RATE);
+        assert_eq!(rtc.next_event(), NEVER);
+        rtc.write(0x08, 62);
+        rtc.write(0x0C, 0x00);
+        rtc.advance(3 + 3600);
+        assert_eq!(rtc.time(), (0, 1, 0, 1));
+    }
+
+    #[test]
+    fn footer() {
+        let mut rtc = Rtc::new();
+        rtc.write(0x0A, 5);
+        let footer = rtc.footer();
+        assert_eq!(footer.len(), FOOTER_LENGTH);
+        assert_eq!(footer[8..12], [5, 0, 0, 0]);
+
+        // 32-bit timestamp of a day, an hour, a minute and a second ago
+        let mut footer = footer[..40].to_vec();
+        footer.extend_from_slice(&((unix_time() - 90061) as u32).to_le_bytes());
+        let mut loaded = Rtc::new();
+        assert!(loaded.load_footer(&footer[..40]).is_err());
+        loaded.load_footer(&footer).unwrap();
+        let (days, hours, minutes, seconds) = loaded.time();
+        assert_eq!((days, hours, minutes), (1, 6, 1));
+        assert!((1..=2).contains(&seconds));
+    }
+
+    #[test]
+    fn host_sync_save_states() {
+        let mut rtc = Rtc::new();
+        rtc.set_host_sync(true);
+        let mut snapshot = StateWriter::snapshot();
+        rtc.save_state(&mut snapshot);
+        let snapshot = snapshot.into_inner();
+        assert_eq!(snapshot[snapshot.len() - 8..], [0; 8]);
+
+        // A user state saved a day ago catches up, a snapshot does not
+        let mut state = StateWriter::new();
+        rtc.save_state(&mut state);
+        let mut state = state.into_inner();
+        let length = state.len();
+        state[length - 8..].copy_from_slice(&(unix_time() - 86400).to_le_bytes());
+
+        rtc.load_state(&mut StateReader::snapshot(&state)).unwrap();
+        assert_eq!(rtc.time(), (0, 0, 0, 0));
+        rtc.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(rtc.time().0, 1);
+    }
+}
@@ -0,0 +1,165 @@
+// Save state serialization
+//
+// A save state is a small header followed by the state of every emulated
+// component, written in a fixed order by the components themselves. All
+// values are stored little-endian, buffers are prefixed by their length.
+
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"RGBS";
+
+/// Version of the save state format, to be bumped every time the layout of
+/// any component state changes.
+pub const VERSION: u32 = 11;
+
+#[derive(Debug)]
+pub struct StateLoadError {
+    pub error: String,
+}
+
+pub(crate) struct StateWriter {
+    data: Vec<u8>,
+    // The state is timestamped with the host time, for the components synced
+    // to it
+    host_time: bool,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter {
+            data: Vec::new(),
+            host_time: true,
+        }
+    }
+
+    /// Writer of a snapshot restored by the emulator itself, without host
+    /// timestamps so that identical states are written identically
+    pub fn snapshot() -> StateWriter {
+        StateWriter { host_time: false, ..StateWriter::new() }
+    }
+
+    pub fn host_time(&self) -> bool {
+        self.host_time
+    }
+
+    pub fn write_header(&mut self, rom_id: u32) {
+        self.data.extend_from_slice(MAGIC);
+        self.write_u32(VERSION);
+        self.write_u32(rom_id);
+    }
+
+    pub fn write_u8(&mut self, value: u8) { self.data.push(value); }
+    pub fn write_bool(&mut self, value: bool) { self.write_u8(value as u8); }
+    pub fn write_u16(&mut self, value: u16) { self.data.extend_from_slice(&value.to_le_bytes()); }
+    pub fn write_u32(&mut self, value: u32) { self.data.extend_from_slice(&value.to_le_bytes()); }
+    pub fn write_u64(&mut self, value: u64) { self.data.extend_from_slice(&value.to_le_bytes()); }
+    pub fn write_usize(&mut self, value: usize) { self.write_u64(value as u64); }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+pub(crate) struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    // The components synced to the host time catch up with the time elapsed
+    // since the state was saved
+    host_time: bool,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader {
+            data,
+            position: 0,
+            host_time: true,
+        }
+    }
+
+    /// Reader of a snapshot, the host time elapsed since it was written is
+    /// ignored
+    pub fn snapshot(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { host_time: false, ..StateReader::new(data) }
+    }
+
+    pub fn host_time(&self) -> bool {
+        self.host_time
+    }
+
+    pub fn read_header(&mut self, rom_id: u32) -> Result<(), StateLoadError> {
+        if self.take(MAGIC.len())? != MAGIC {
+            return Err(StateLoadError::from("Not a save state."));
+        }
+        let version = self.read_u32()?;
+        if version != VERSION {
+            return Err(StateLoadError {
+                error: format!("Unsupported save state version {} (expected {}).", version, VERSION),
+            });
+        }
+        if self.read_u32()? != rom_id {
+            return Err(StateLoadError::from("Save state was created for another ROM."));
+        }
+        Ok(())
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateLoadError> { Ok(self.take(1)?[0]) }
+    pub fn read_bool(&mut self) -> Result<bool, StateLoadError> { Ok(self.read_u8()? != 0) }
+    pub fn read_u16(&mut self) -> Result<u16, StateLoadError> { Ok(u16::from_le_bytes(self.take_array()?)) }
+    pub fn read_u32(&mut self) -> Result<u32, StateLoadError> { Ok(u32::from_le_bytes(self.take_array()?)) }
+    pub fn read_u64(&mut self) -> Result<u64, StateLoadError> { Ok(u64::from_le_bytes(self.take_array()?)) }
+    pub fn read_usize(&mut self) -> Result<usize, StateLoadError> { Ok(self.read_u64()? as usize) }
+
+    /// Read a buffer into `dest`, the buffer must have been saved with the same size
+    pub fn read_bytes_into(&mut self, dest: &mut [u8]) -> Result<(), StateLoadError> {
+        let length = self.read_u32()? as usize;
+        if length != dest.len() {
+            return Err(StateLoadError {
+                error: format!("Buffer size mismatch: expected {} bytes, found {}.", dest.len(), length),
+            });
+        }
+        dest.copy_from_slice(self.take(length)?);
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<(), StateLoadError> {
+        if self.position != self.data.len() {
+            return Err(StateLoadError::from("Trailing data at the end of the save state."));
+        }
+        Ok(())
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], StateLoadError> {
+        if self.data.len() - self.position < length {
+            return Err(StateLoadError::from("Save state is truncated."));
+        }
+        let slice = &self.data[self.position..self.position+length];
+        self.position += length;
+        Ok(slice)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], StateLoadError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+}
+
+impl fmt::Display for StateLoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl From<&'static str> for StateLoadError {
+    fn from(err: &'static str) -> StateLoadError {
+        StateLoadError {
+            error: err.to_string(),
+        }
+    }
+}
@@ -0,0 +1,81 @@
+// Peripheral event scheduler
+//
+// The timer, the PPU, the APU and the cart clock are not run on every clock
+// cycle: each of them registers the cycle of its next event (a TIMA overflow,
+// a PPU mode transition, an audio sample, an RTC second), and is only run when
+// that cycle is reached. In between, their state is caught up on demand when
+// the CPU accesses their registers.
+//
+// The scheduled times are derived from the peripherals state, they are not
+// part of the save states and are rebuilt on load.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Event {
+    Timer,
+    Video,
+    Audio,
+    Cart,
+}
+
+const EVENTS: [Event; 4] = [Event::Timer, Event::Video, Event::Audio, Event::Cart];
+
+/// Cycle of an event never happening
+pub(crate) const NEVER: usize = usize::MAX;
+
+pub(crate) struct Scheduler {
+    times: [usize; EVENTS.len()],
+    // Earliest of the scheduled times
+    next: usize,
+}
+
+impl Scheduler {
+    pub(crate) fn new() -> Scheduler {
+        Scheduler {
+            times: [NEVER; EVENTS.len()],
+            next: NEVER,
+        }
+    }
+
+    /// Set the cycle of the next `event`, replacing the previous one
+    pub(crate) fn schedule(&mut self, event: Event, cycle: usize) {
+        self.times[event as usize] = cycle;
+        self.next = self.times.iter().copied().min().unwrap_or(NEVER);
+    }
+
+    /// Cycle of the earliest event
+    pub(crate) fn next(&self) -> usize {
+        self.next
+    }
+
+    /// Take the earliest event due at `cycle`, it has to be scheduled again
+    pub(crate) fn pop(&mut self, cycle: usize) -> Option<Event> {
+        if self.next > cycle {
+            return None;
+        }
+
+        let event = EVENTS.into_iter().find(|&event| self.times[event as usize] == self.next)?;
+        self.schedule(event, NEVER);
+        Some(event)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pop_in_order() {
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(Event::Video, 80);
+        scheduler.schedule(Event::Audio, 96);
+        scheduler.schedule(Event::Timer, 40);
+        scheduler.schedule(Event::Timer, 120);
+
+        assert_eq!(scheduler.pop(79), None);
+        assert_eq!(scheduler.pop(100), Some(Event::Video));
+        assert_eq!(scheduler.pop(100), Some(Event::Audio));
+        assert_eq!(scheduler.pop(100), None);
+        assert_eq!(scheduler.pop(120), Some(Event::Timer));
+        assert_eq!(scheduler.pop(NEVER - 1), None);
+    }
+}
@@ -0,0 +1,94 @@
+// Serial port
+//
+// No link cable partner is emulated: transfers using the internal clock
+// complete immediately and shift in 0xFF, as when nothing is connected. The
+// transferred bytes are kept in `output` so that they can be displayed or
+// checked by the emulator user (test ROMs report their results this way).
+// Only the last bytes are kept when the output is not drained by the user.
+
+use crate::cpu;
+use crate::savestate::{StateLoadError, StateReader, StateWriter};
+
+/// Maximum length of `Serial::output`, the oldest half is dropped when full
+pub const OUTPUT_LIMIT: usize = 64*1024;
+
+pub struct Serial {
+    reg_sb: u8,
+    reg_sc: u8,
+
+    pub output: Vec<u8>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            reg_sb: 0,
+            reg_sc: 0,
+
+            output: Vec::new(),
+        }
+    }
+
+    // Memory access
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            0xff01 => self.reg_sb,
+            0xff02 => self.reg_sc | 0x7e,
+            _ => panic!("Read serial address decoding bug"),
+        }
+    }
+
+    // Returns the interrupt flags raised by the write
+    pub fn write(&mut self, address: u16, data: u8) -> u8 {
+        match address {
+            0xff01 => self.reg_sb = data,
+            0xff02 => {
+                self.reg_sc = data & 0x81;
+                if self.reg_sc == 0x81 {
+                    if self.output.len() == OUTPUT_LIMIT {
+                        self.output.drain(..OUTPUT_LIMIT/2);
+                    }
+                    self.output.push(self.reg_sb);
+                    self.reg_sb = 0xff;
+                    self.reg_sc &= 0x01;
+                    return cpu::IRQ_SERIAL;
+                }
+            },
+            _ => panic!("Write serial address decoding bug"),
+        }
+        0
+    }
+
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(self.reg_sb);
+        state.write_u8(self.reg_sc);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        self.reg_sb = state.read_u8()?;
+        self.reg_sc = state.read_u8()?;
+        Ok(())
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn output_limit() {
+        let mut serial = Serial::new();
+        for byte in 0..OUTPUT_LIMIT + 10 {
+            serial.write(0xff01, byte as u8);
+            serial.write(0xff02, 0x81);
+        }
+        assert_eq!(serial.output.len(), OUTPUT_LIMIT/2 + 10);
+        assert_eq!(*serial.output.last().unwrap(), (OUTPUT_LIMIT + 9) as u8);
+    }
+}
@@ -0,0 +1,184 @@
+// Debug symbols
+//
+// Loads the `bank:address label` symbol files generated by RGBDS (`.sym`)
+// and the no$gmb symbol format, so that addresses can be shown as
+// `label+offset`. Lines starting with ';' are comments and section headers
+// like `[labels]` are ignored.
+//
+// Lookups are bank-aware: addresses in 0x4000-0x7fff use the labels of the ROM
+// bank currently mapped by the cart, ROM0 addresses the labels of bank 0. Other
+// memories (WRAM, HRAM, ...) match labels of any bank. An address only gets a
+// label from the same memory region, so that a RAM variable is never shown as
+// an offset from a ROM label.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+
+#[derive(Debug, Clone, Default)]
+pub struct Symbols {
+    // Labels by bank, then by address
+    labels: BTreeMap<u16, BTreeMap<u16, String>>,
+}
+
+#[derive(Debug)]
+pub struct SymbolLoadError {
+    pub error: String,
+}
+
+/// Label at an address, displayed as `label` or `label+$offset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Location<'a> {
+    pub label: &'a str,
+    pub offset: u16,
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Symbols::default()
+    }
+
+    pub fn load(path: &str) -> Result<Symbols, SymbolLoadError> {
+        Symbols::parse(&fs::read_to_string(path)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Symbols, SymbolLoadError> {
+        let mut symbols = Symbols::new();
+
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split(';').next().unwrap_or("").trim();
+            if line.is_empty() || line.starts_with('[') {
+                continue;
+            }
+
+            let parsed = line.split_once(char::is_whitespace).and_then(|(location, label)| {
+                let (bank, address) = location.split_once(':')?;
+                Some((u16::from_str_radix(bank, 16).ok()?, u16::from_str_radix(address, 16).ok()?, label.trim()))
+            });
+            match parsed {
+                Some((bank, address, label)) if !label.is_empty() => symbols.insert(bank, address, label),
+                _ => return Err(SymbolLoadError { error: format!("Invalid symbol at line {}: {}", number + 1, line) }),
+            }
+        }
+
+        Ok(symbols)
+    }
+
+    pub fn insert(&mut self, bank: u16, address: u16, label: &str) {
+        self.labels.entry(bank).or_default().insert(address, label.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.values().map(BTreeMap::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Closest label at or before `address`, `rom_bank` is the bank mapped
+    /// at 0x4000-0x7fff
+    pub fn lookup(&self, address: u16, rom_bank: usize) -> Option<Location<'_>> {
+        match address {
+            0x0000..=0x3fff => self.closest(0, address),
+            0x4000..=0x7fff => self.closest(rom_bank as u16, address),
+            _ => {
+                // Any bank, the closest label wins
+                self.labels.keys()
+                    .filter_map(|&bank| self.closest(bank, address))
+                    .min_by_key(|location| location.offset)
+            },
+        }
+    }
+
+    /// Address of a label, as `(bank, address)`
+    pub fn find(&self, label: &str) -> Option<(u16, u16)> {
+        self.labels.iter().find_map(|(&bank, labels)| {
+            labels.iter().find(|(_, name)| name.as_str() == label).map(|(&address, _)| (bank, address))
+        })
+    }
+
+    // Closest label of a bank in the memory region of the address
+    fn closest(&self, bank: u16, address: u16) -> Option<Location<'_>> {
+        let (&label_address, label) = self.labels.get(&bank)?.range(region_start(address)..=address).next_back()?;
+        Some(Location { label, offset: address - label_address })
+    }
+}
+
+// Start of the memory region containing an address
+fn region_start(address: u16) -> u16 {
+    match address {
+        0x0000..=0x3fff => 0x0000,
+        0x4000..=0x7fff => 0x4000,
+        0x8000..=0x9fff => 0x8000,
+        0xa000..=0xbfff => 0xa000,
+        0xc000..=0xdfff => 0xc000,
+        0xe000..=0xfdff => 0xe000,
+        0xfe00..=0xfeff => 0xfe00,
+        0xff00..=0xff7f => 0xff00,
+        _ => 0xff80,
+    }
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.offset == 0 {
+            write!(f, "{}", self.label)
+        } else {
+            write!(f, "{}+${:x}", self.label, self.offset)
+        }
+    }
+}
+
+impl From<io::Error> for SymbolLoadError {
+    fn from(err: io::Error) -> SymbolLoadError {
+        SymbolLoadError { error: err.to_string() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RGBDS_SYM: &str = "\
+; File generated by rgblink
+00:0150 Main
+00:0158 Main.loop
+01:4000 BankedFunction
+02:4000 OtherBank
+00:c000 wCounter
+00:ff80 hFlags
+";
+
+    fn location(symbols: &Symbols, address: u16, rom_bank: usize) -> Option<String> {
+        symbols.lookup(address, rom_bank).map(|location| location.to_string())
+    }
+
+    #[test]
+    fn rgbds_lookup() {
+        let symbols = Symbols::parse(RGBDS_SYM).unwrap();
+        assert_eq!(symbols.len(), 6);
+
+        assert_eq!(location(&symbols, 0x0150, 1).as_deref(), Some("Main"));
+        assert_eq!(location(&symbols, 0x015a, 1).as_deref(), Some("Main.loop+$2"));
+        assert_eq!(location(&symbols, 0x0100, 1), None);
+        assert_eq!(location(&symbols, 0x4010, 1).as_deref(), Some("BankedFunction+$10"));
+        assert_eq!(location(&symbols, 0x4010, 2).as_deref(), Some("OtherBank+$10"));
+        assert_eq!(location(&symbols, 0x4010, 3), None);
+        assert_eq!(location(&symbols, 0xc001, 1).as_deref(), Some("wCounter+$1"));
+        assert_eq!(location(&symbols, 0xff81, 1).as_deref(), Some("hFlags+$1"));
+        assert_eq!(location(&symbols, 0xff40, 1), None);
+
+        assert_eq!(symbols.find("Main.loop"), Some((0, 0x0158)));
+    }
+
+    #[test]
+    fn nocash_format() {
+        let symbols = Symbols::parse("[labels]\n0000:0100 Entry\n0001:4000 Banked\n").unwrap();
+        assert_eq!(location(&symbols, 0x0101, 1).as_deref(), Some("Entry+$1"));
+        assert_eq!(location(&symbols, 0x4000, 1).as_deref(), Some("Banked"));
+
+        assert!(Symbols::parse("00:zz00 Broken\n").is_err());
+    }
+}
@@ -1,5 +1,8 @@
 use crate::cpu;
+use crate::scheduler::NEVER;
+use crate::savestate::{StateLoadError, StateReader, StateWriter};
 
+#[derive(Clone)]
 pub struct Timer {
     div_full: u16,
     reg_tima: u8,
@@ -44,33 +47,99 @@ impl Timer {
         };
     }
 
-    // Run!
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u16(self.div_full);
+        state.write_u8(self.reg_tima);
+        state.write_u8(self.reg_tma);
+        state.write_u8(self.reg_tac);
+        state.write_usize(self.prev_cycle);
+        state.write_bool(self.prev_timer_inc);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        self.div_full = state.read_u16()?;
+        self.reg_tima = state.read_u8()?;
+        self.reg_tma = state.read_u8()?;
+        self.reg_tac = state.read_u8()?;
+        self.prev_cycle = state.read_usize()?;
+        self.prev_timer_inc = state.read_bool()?;
+        Ok(())
+    }
+
+    // Run up to `cycle`, DIV counts every clock cycle and TIMA on the falling
+    // edges of the DIV bit selected by TAC
     pub fn step(&mut self, cycle: usize) -> u8 {
-        let mut irq = 0;
-        let step = cycle.wrapping_sub(self.prev_cycle) as u16;
+        let steps = cycle.saturating_sub(self.prev_cycle);
         self.prev_cycle = cycle;
+        if steps == 0 {
+            return 0;
+        }
 
-        for _ in 0..step {
-            self.div_full = self.div_full.wrapping_add(1);
-
-            let timer_inc = match self.reg_tac&0x03 {
-                0 => self.div_full&(1<<9) != 0,
-                1 => self.div_full&(1<<3) != 0,
-                2 => self.div_full&(1<<5) != 0,
-                3 => self.div_full&(1<<7) != 0,
-                _ => panic!("Timer bug"),
-            };
-
-            if self.reg_tac&0x04 != 0 && self.prev_timer_inc && !timer_inc {
-                self.reg_tima = self.reg_tima.wrapping_add(1);
-                if self.reg_tima == 0 {
-                    self.reg_tima = self.reg_tma;
-                    irq = cpu::IRQ_TIMER;
-                }
-            }
-            self.prev_timer_inc = timer_inc;
+        let div = self.div_full as usize;
+        let bit = self.selected_bit();
+        // The first step compares with the previous level, which differs from
+        // the DIV bit after a DIV reset or a TAC change
+        let first_edge = self.prev_timer_inc && (div + 1)&(1<<bit) == 0;
+        let period = 2<<bit;
+        let edges = first_edge as usize + (div + steps)/period - (div + 1)/period;
+
+        self.div_full = (div + steps) as u16;
+        self.prev_timer_inc = self.div_full&(1<<bit) != 0;
+
+        if self.reg_tac&0x04 != 0 { self.count(edges) } else { 0 }
+    }
+
+    /// Cycle at which TIMA overflows next, at the current registers
+    pub(crate) fn next_event(&self) -> usize {
+        if self.reg_tac&0x04 == 0 {
+            return NEVER;
         }
 
+        let div = self.div_full as usize;
+        let bit = self.selected_bit();
+        let first_edge = self.prev_timer_inc && (div + 1)&(1<<bit) == 0;
+        let period = 2<<bit;
+        let edges = 0x100 - self.reg_tima as usize;
+        // Steps of the edges following the first step
+        let second = 2 + (period - (div + 2)%period)%period;
+
+        self.prev_cycle + match (first_edge, edges) {
+            (true, 1) => 1,
+            (true, _) => second + (edges - 2)*period,
+            (false, _) => second + (edges - 1)*period,
+        }
+    }
+
+    // Timer state at `cycle`, without running the timer itself
+    pub(crate) fn at(&self, cycle: usize) -> Timer {
+        let mut timer = self.clone();
+        timer.step(cycle);
+        timer
+    }
+
+    fn selected_bit(&self) -> u32 {
+        match self.reg_tac&0x03 {
+            0 => 9,
+            1 => 3,
+            2 => 5,
+            3 => 7,
+            _ => panic!("Timer bug"),
+        }
+    }
+
+    // Increment TIMA `edges` times, reloading TMA on overflows
+    fn count(&mut self, mut edges: usize) -> u8 {
+        let mut irq = 0;
+        while edges > 0 {
+            let left = 0x100 - self.reg_tima as usize;
+            if edges < left {
+                self.reg_tima += edges as u8;
+                break;
+            }
+            edges -= left;
+            self.reg_tima = self.reg_tma;
+            irq = cpu::IRQ_TIMER;
+        }
         irq
     }
 }
@@ -80,3 +149,61 @@ impl Default for Timer {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reference timer, run one clock cycle at a time
+    fn step_cycles(timer: &mut Timer, cycle: usize) -> u8 {
+        let mut irq = 0;
+        for _ in timer.prev_cycle..cycle {
+            timer.div_full = timer.div_full.wrapping_add(1);
+            let timer_inc = timer.div_full&(1<<timer.selected_bit()) != 0;
+            if timer.reg_tac&0x04 != 0 && timer.prev_timer_inc && !timer_inc {
+                irq |= timer.count(1);
+            }
+            timer.prev_timer_inc = timer_inc;
+        }
+        timer.prev_cycle = cycle;
+        irq
+    }
+
+    #[test]
+    fn scheduled_matches_cycle_stepping() {
+        let mut reference = Timer::new();
+        let mut timer = Timer::new();
+        let mut next_event = timer.next_event();
+        let mut interrupts = 0;
+        let mut seed = 0x1234_5678u32;
+        let mut random = || {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (seed >> 16) as u8
+        };
+
+        for tick in 1..200_000 {
+            let cycle = tick*4;
+            let mut irq = 0;
+            if cycle >= next_event {
+                irq = timer.step(cycle);
+                next_event = timer.next_event();
+            }
+            assert_eq!(irq, step_cycles(&mut reference, cycle), "IRQ at cycle {}", cycle);
+            interrupts += (irq != 0) as usize;
+
+            for address in 0xff04..=0xff07 {
+                assert_eq!(timer.at(cycle).read(address), reference.read(address), "${:04x} at cycle {}", address, cycle);
+            }
+
+            if random() < 4 {
+                let (address, data) = (0xff04 + (random()%4) as u16, random());
+                let data = if address == 0xff07 { data | 0x04 } else { data };
+                timer.step(cycle);
+                timer.write(address, data);
+                next_event = timer.next_event();
+                reference.write(address, data);
+            }
+        }
+        assert!(interrupts > 0);
+    }
+}
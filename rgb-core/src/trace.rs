@@ -0,0 +1,79 @@
+// CPU trace log
+//
+// Writes one line per executed instruction in the Gameboy Doctor format, the
+// CPU state before the instruction and the 4 bytes at PC:
+//
+//   A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+//
+// This format is used by other emulators and test tools, so that logs can be
+// diffed to find the first divergent instruction. The clock cycle count at
+// the start of the instruction can optionally be appended as `CY:<cycles>`.
+// When symbols are given to the tracer, the label of PC is also appended as a
+// `; label+offset` comment, which tools reading the plain format may reject.
+//
+// Nothing is logged while the CPU is halted, stopped or dispatching an
+// interrupt. The tracer is attached at runtime with `Dmg::set_tracer()`.
+
+use std::io::{self, Write};
+
+use crate::bus::Bus;
+use crate::cpu::Regs;
+use crate::symbols::Symbols;
+
+pub struct Tracer {
+    writer: Box<dyn Write + Send>,
+    cycles: bool,
+    symbols: Option<Symbols>,
+    /// First write error, the tracer stops writing after an error
+    error: Option<io::Error>,
+}
+
+impl Tracer {
+    /// Trace to a writer, a buffered one is recommended as a line is written
+    /// for every instruction
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Tracer {
+        Tracer { writer: Box::new(writer), cycles: false, symbols: None, error: None }
+    }
+
+    /// Append the clock cycle count to each line
+    pub fn with_cycles(mut self, cycles: bool) -> Tracer {
+        self.cycles = cycles;
+        self
+    }
+
+    /// Append the label of PC to each line as a comment, not part of the
+    /// Gameboy Doctor format
+    pub fn with_symbols(mut self, symbols: Symbols) -> Tracer {
+        self.symbols = Some(symbols);
+        self
+    }
+
+    pub(crate) fn trace<B: Bus + ?Sized>(&mut self, regs: &Regs, bus: &B, cycle: usize, rom_bank: usize) {
+        if self.error.is_some() {
+            return;
+        }
+
+        let pcmem: Vec<String> = (0..4).map(|offset| format!("{:02X}", bus.peek(regs.pc.wrapping_add(offset)))).collect();
+        let mut line = format!("A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+                               regs.a, regs.f, regs.b, regs.c, regs.d, regs.e, regs.h, regs.l, regs.sp, regs.pc,
+                               pcmem.join(","));
+        if self.cycles {
+            line.push_str(&format!(" CY:{}", cycle));
+        }
+        if let Some(location) = self.symbols.as_ref().and_then(|symbols| symbols.lookup(regs.pc, rom_bank)) {
+            line.push_str(&format!(" ; {}", location));
+        }
+
+        if let Err(err) = writeln!(self.writer, "{}", line) {
+            self.error = Some(err);
+        }
+    }
+
+    /// Flush the log, returns the first error raised while tracing
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.writer.flush(),
+        }
+    }
+}
@@ -4,6 +4,7 @@
 #![allow(unused_variables)]
 
 use crate::cpu;
+use crate::savestate::{StateLoadError, StateReader, StateWriter};
 
 enum Mode {
     Mode0,
@@ -99,6 +100,7 @@ impl Video {
         }
     }
 
+    // Run the mode transition due at `cycle`, if any
     pub fn step(&mut self, cycle: usize) -> u8 {
         let mut irq = 0;
 
@@ -168,13 +170,59 @@ impl Video {
                 },
             };
         };
+        irq
+    }
 
+    /// Cycle of the next mode transition
+    pub(crate) fn next_event(&self) -> usize {
+        self.next_event
+    }
+
+    /// The LY=LYC interrupt, raised as long as the coincidence lasts
+    pub(crate) fn lyc_interrupt(&self) -> u8 {
         if self.registers[STAT] & (1<<6) != 0 && self.registers[LYC] == self.registers[LY] {
-            irq |= cpu::IRQ_LCDSTAT;
+            cpu::IRQ_LCDSTAT
+        } else {
+            0
         }
-        irq
     }
 
+    pub(crate) fn save_state(&self, state: &mut StateWriter) {
+        state.write_u8(match self.mode {
+            Mode::Mode0 => 0,
+            Mode::Mode1 => 1,
+            Mode::Mode2 => 2,
+            Mode::Mode3 => 3,
+        });
+        state.write_usize(self.next_event);
+        state.write_bool(self.enabled);
+        state.write_bytes(&self.vram);
+        state.write_bytes(&self.oam);
+        state.write_bytes(&self.registers);
+        state.write_bytes(&self.screen);
+        state.write_bool(self.image_ready);
+    }
+
+    pub(crate) fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateLoadError> {
+        self.mode = match state.read_u8()? {
+            0 => Mode::Mode0,
+            1 => Mode::Mode1,
+            2 => Mode::Mode2,
+            3 => Mode::Mode3,
+            _ => return Err(StateLoadError::from("Invalid video mode.")),
+        };
+        self.next_event = state.read_usize()?;
+        self.enabled = state.read_bool()?;
+        state.read_bytes_into(&mut self.vram)?;
+        state.read_bytes_into(&mut self.oam)?;
+        state.read_bytes_into(&mut self.registers)?;
+        state.read_bytes_into(&mut self.screen)?;
+        self.image_ready = state.read_bool()?;
+        Ok(())
+    }
+
+    // The VRAM and OAM access restrictions are kept disabled for now
+    #[allow(clippy::match_single_binding)]
     pub fn read(&self, address: u16) -> u8 {
         match address {
             _ if (0x8000..0xA000).contains(&address) => match self.mode {
@@ -190,6 +238,7 @@ impl Video {
         }
     }
 
+    #[allow(clippy::match_single_binding)]
     pub fn write(&mut self, address:u16, data: u8) {
         match address {
             _ if (0x8000..0xA000).contains(&address) => match self.mode {
@@ -266,7 +315,7 @@ impl Video {
                     let tile_line = wy & 0x07;
 
                     let tile_map_addr: usize = if self.registers[LCDC]&(1<<6)==0 {0x1800} else {0x1c00};
-                    let tile_id = self.vram[tile_map_addr + tile_pos] as u8;
+                    let tile_id = self.vram[tile_map_addr + tile_pos];
 
                     pixel.palette = Palette::BGP;
                     pixel.color = Video::get_tile_color(&self.vram, self.registers[LCDC]&(1<<6)!=0,
@@ -278,14 +327,12 @@ impl Video {
 
     fn get_bg_tile_pixel(&mut self, tile_pos: usize, line: usize, col: usize) -> usize {
         let tile_map_addr: usize = if self.registers[LCDC]&0x08==0 {0x1800} else {0x1c00};
-        let tile_id = self.vram[tile_map_addr + tile_pos] as u8;
-        let tile_address;
-
-        if self.registers[LCDC]&0x10 == 0 {
-            tile_address = (0x1000 + (((tile_id as i8) as isize)*16)) as usize;
+        let tile_id = self.vram[tile_map_addr + tile_pos];
+        let tile_address = if self.registers[LCDC]&0x10 == 0 {
+            (0x1000 + (((tile_id as i8) as isize)*16)) as usize
         } else {
-            tile_address = (tile_id as usize)*16;
-        }
+            (tile_id as usize)*16
+        };
 
         let low = self.vram[tile_address+(2*line)] as usize;
         let high = self.vram[tile_address+(2*line)+1] as usize;
@@ -294,13 +341,11 @@ impl Video {
     }
 
     fn get_tile_color(vram: &[u8], signed_id: bool, tile_id: u8, col: usize, line: usize) -> usize {
-        let tile_address;
-
-        if signed_id {
-            tile_address = (0x1000 + (((tile_id as i8) as isize)*16)) as usize;
+        let tile_address = if signed_id {
+            (0x1000 + (((tile_id as i8) as isize)*16)) as usize
         } else {
-            tile_address = (tile_id as usize) * 16;
-        }
+            (tile_id as usize) * 16
+        };
 
         let low = vram[tile_address+(2*line)] as usize;
         let high = vram[tile_address+(2*line)+1] as usize;
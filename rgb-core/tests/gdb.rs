@@ -0,0 +1,140 @@
+// GDB remote serial protocol server driven by a scripted client
+
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread;
+
+use rgb_core::cart::Cart;
+use rgb_core::gdb::{GdbServer, SessionEnd};
+use rgb_core::Dmg;
+
+struct Client {
+    stream: TcpStream,
+}
+
+impl Client {
+    // Send a command and return the reply
+    fn command(&mut self, data: &str) -> String {
+        self.command_bytes(data.as_bytes())
+    }
+
+    fn command_bytes(&mut self, data: &[u8]) -> String {
+        let checksum = data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte));
+        self.stream.write_all(b"$").unwrap();
+        self.stream.write_all(data).unwrap();
+        write!(self.stream, "#{:02x}", checksum).unwrap();
+
+        assert_eq!(self.read_byte(), b'+', "command {:?} not acknowledged", String::from_utf8_lossy(data));
+        assert_eq!(self.read_byte(), b'$');
+        let mut reply = Vec::new();
+        loop {
+            match self.read_byte() {
+                b'#' => break,
+                byte => reply.push(byte),
+            }
+        }
+        let mut checksum = [0; 2];
+        self.stream.read_exact(&mut checksum).unwrap();
+        self.stream.write_all(b"+").unwrap();
+
+        String::from_utf8(reply).unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let mut byte = [0];
+        self.stream.read_exact(&mut byte).unwrap();
+        byte[0]
+    }
+}
+
+// $0100: LD HL,$C000; CALL $0150; JR $0103
+// $0150: INC A; LD (HL),A; RET
+fn test_dmg() -> Dmg {
+    let mut rom = vec![0; 0x8000];
+    rom[0x100..0x108].copy_from_slice(&[0x21, 0x00, 0xC0, 0xCD, 0x50, 0x01, 0x18, 0xFB]);
+    rom[0x150..0x153].copy_from_slice(&[0x3C, 0x77, 0xC9]);
+    let mut dmg = Dmg::new(Cart::create_from_slice(&rom));
+    dmg.cpu.set_pc(0x100);
+    dmg.cpu.regs_mut().sp = 0xD000;
+    dmg.cpu.mem.write(0xff50, 1);
+    dmg
+}
+
+#[test]
+fn gdb_session() {
+    let server = GdbServer::bind(0).unwrap();
+    let port = server.port().unwrap();
+    let emulator = thread::spawn(move || {
+        let mut dmg = test_dmg();
+        let end = server.serve(&mut dmg).unwrap();
+        (end, dmg.cpu.get_pc())
+    });
+
+    let mut client = Client { stream: TcpStream::connect(("127.0.0.1", port)).unwrap() };
+
+    assert!(client.command("qSupported:swbreak+").contains("qXfer:features:read+"));
+    assert_eq!(client.command("?"), "S05");
+    assert_eq!(client.command("vMustReplyEmpty"), "");
+
+    // Target description, read in chunks
+    let mut description = String::new();
+    loop {
+        let reply = client.command(&format!("qXfer:features:read:target.xml:{:x},40", description.len()));
+        description.push_str(&reply[1..]);
+        if reply.starts_with('l') {
+            break;
+        }
+        assert!(reply.starts_with('m'));
+    }
+    assert!(description.starts_with("<?xml"));
+    assert_eq!(description.matches("<reg ").count(), 6);
+
+    // AF, BC, DE, HL, SP, PC little-endian
+    let registers = client.command("g");
+    assert_eq!(registers.len(), 24);
+    assert_eq!(&registers[16..], "00d00001");
+    assert_eq!(client.command("p5"), "0001");
+
+    // Breakpoint and continue
+    assert_eq!(client.command("Z0,150,1"), "OK");
+    assert_eq!(client.command("c"), "S05");
+    assert_eq!(client.command("p5"), "5001");
+    assert_eq!(client.command("p4"), "fecf");
+    assert_eq!(client.command("z0,150,1"), "OK");
+
+    // Single step
+    assert_eq!(client.command("s"), "S05");
+    assert_eq!(client.command("p5"), "5101");
+
+    // Memory access
+    assert_eq!(client.command("mcffe,2"), "0601");
+    assert_eq!(client.command("Mc000,2:4243"), "OK");
+    assert_eq!(client.command("mc000,2"), "4243");
+
+    // Reads are cut to the packet size
+    assert_eq!(client.command("m0,ffffffff").len(), 0x1000);
+
+    // Non-ASCII bytes are rejected
+    let mut registers = b"Gaa\xff".to_vec();
+    registers.resize(1 + 24, b'a');
+    assert_eq!(client.command_bytes(&registers), "E01");
+    assert_eq!(client.command_bytes(b"Mc000,1:\xff\xff"), "E01");
+    assert_eq!(client.command_bytes(b"P0=\xff\xff\xff\xff"), "E01");
+
+    // Write watchpoint
+    assert_eq!(client.command("P0=0005"), "OK");
+    assert_eq!(client.command("Z2,c000,1"), "OK");
+    assert_eq!(client.command("c"), "T05watch:c000;");
+    assert_eq!(client.command("mc000,1"), "05");
+    assert_eq!(client.command("z2,c000,1"), "OK");
+
+    // Access watchpoint
+    assert_eq!(client.command("Z4,c000,1"), "OK");
+    assert_eq!(client.command("c"), "T05awatch:c000;");
+    assert_eq!(client.command("z4,c000,1"), "OK");
+
+    assert_eq!(client.command("D"), "OK");
+    let (end, pc) = emulator.join().unwrap();
+    assert_eq!(end, SessionEnd::Detached);
+    assert_eq!(pc, 0x152);
+}
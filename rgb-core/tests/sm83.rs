@@ -0,0 +1,236 @@
+// Single instruction CPU tests in the SM83 SingleStepTests JSON format
+//
+// Each JSON file contains a list of tests for one opcode, with the CPU
+// registers and memory content before and after running the instruction and
+// the bus activity of every M-cycle. The CPU is run against a flat 64KiB RAM.
+//
+// The test vectors are not distributed with the emulator. They are read from
+// the directory pointed to by the RGB_SM83_TESTS environment variable, or
+// from `tests/sm83` by default, and the test is skipped if the directory does
+// not exist. Files listed in `known_failures.txt` in that directory do not
+// fail the test.
+
+use std::fs;
+use std::panic;
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use rgb_core::bus::{Bus, FlatRam};
+use rgb_core::cpu::Cpu;
+
+const IE_ADDRESS: u16 = 0xffff;
+
+/// Maximum number of failures reported in details per file
+const MAX_REPORTED_FAILURES: usize = 3;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+// Flat RAM recording the CPU accesses
+struct RecordingBus {
+    ram: FlatRam,
+    accesses: Vec<Access>,
+}
+
+impl Bus for RecordingBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let data = self.ram.read(address);
+        self.accesses.push(Access::Read(address, data));
+        data
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.ram.write(address, data);
+        self.accesses.push(Access::Write(address, data));
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.ram.peek(address)
+    }
+
+    fn tick(&mut self, cycles: usize) {
+        self.ram.tick(cycles);
+    }
+
+    fn pending_interrupts(&self) -> u8 {
+        self.ram.pending_interrupts()
+    }
+
+    fn acknowledge_interrupt(&mut self, interrupt: u8) {
+        self.ram.acknowledge_interrupt(interrupt);
+    }
+}
+
+#[test]
+fn sm83_single_step() {
+    let directory = std::env::var("RGB_SM83_TESTS").map(PathBuf::from)
+                        .unwrap_or_else(|_| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/sm83"));
+    if !directory.is_dir() {
+        println!("SM83 test directory {:?} not found, skipping.", directory);
+        return;
+    }
+
+    let known_failures: Vec<String> = fs::read_to_string(directory.join("known_failures.txt"))
+                                          .unwrap_or_default()
+                                          .lines()
+                                          .map(|line| line.trim().to_string())
+                                          .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                                          .collect();
+
+    let mut files: Vec<PathBuf> = fs::read_dir(&directory).unwrap()
+                                      .flatten()
+                                      .map(|entry| entry.path())
+                                      .filter(|path| path.extension().is_some_and(|extension| extension == "json"))
+                                      .collect();
+    files.sort();
+
+    // A vector panicking in the CPU only fails its opcode, silence the
+    // default panic output
+    panic::set_hook(Box::new(|_| {}));
+
+    let mut regressions = Vec::new();
+    for file in files {
+        let name = file.file_name().unwrap().to_string_lossy().to_string();
+        let tests: Value = serde_json::from_str(&fs::read_to_string(&file).unwrap()).unwrap();
+        let tests = tests.as_array().unwrap();
+
+        let failures: Vec<String> = tests.iter().filter_map(|test| {
+            let test_name = test["name"].as_str().unwrap_or("").to_string();
+            let result = panic::catch_unwind(|| run_test(test))
+                             .unwrap_or_else(|_| Err(String::from("panicked")));
+            result.err().map(|error| format!("{}: {}", test_name, error))
+        }).collect();
+
+        let known_failure = known_failures.contains(&name);
+        if failures.is_empty() {
+            println!("{:12} {}/{} passed", name, tests.len(), tests.len());
+        } else {
+            println!("{:12} {}/{} passed{}", name, tests.len() - failures.len(), tests.len(),
+                     if known_failure { " (known failure)" } else { "" });
+            for failure in failures.iter().take(MAX_REPORTED_FAILURES) {
+                println!("    {}", failure);
+            }
+            if !known_failure {
+                regressions.push(name);
+            }
+        }
+    }
+
+    let _ = panic::take_hook();
+    assert!(regressions.is_empty(), "Failing opcodes: {:?}", regressions);
+}
+
+fn run_test(test: &Value) -> Result<(), String> {
+    let initial = &test["initial"];
+    let expected = &test["final"];
+
+    // Some versions of the tests start with PC already past the opcode
+    // (prefetched during the previous instruction) and end with the next
+    // opcode fetched, they are run from the opcode instead.
+    let opcode = test["name"].as_str().and_then(|name| name.split_whitespace().next())
+                             .and_then(|opcode| u8::from_str_radix(opcode, 16).ok());
+    let initial_pc = field(initial, "pc") as u16;
+    let prefetched = opcode.is_some_and(|opcode| {
+        ram_value(initial, initial_pc) != Some(opcode) &&
+            ram_value(initial, initial_pc.wrapping_sub(1)) == Some(opcode)
+    });
+    let pc_offset = if prefetched { 1 } else { 0 };
+
+    let mut bus = RecordingBus { ram: FlatRam::new(), accesses: Vec::new() };
+    for entry in initial["ram"].as_array().unwrap() {
+        bus.ram.memory[entry[0].as_u64().unwrap() as usize] = entry[1].as_u64().unwrap() as u8;
+    }
+    if let Some(ie) = initial.get("ie").and_then(Value::as_u64) {
+        bus.ram.memory[IE_ADDRESS as usize] = ie as u8;
+    }
+
+    let mut cpu = Cpu::with_bus(bus);
+    {
+        let regs = cpu.regs_mut();
+        regs.a = field(initial, "a") as u8;
+        regs.b = field(initial, "b") as u8;
+        regs.c = field(initial, "c") as u8;
+        regs.d = field(initial, "d") as u8;
+        regs.e = field(initial, "e") as u8;
+        regs.f = field(initial, "f") as u8;
+        regs.h = field(initial, "h") as u8;
+        regs.l = field(initial, "l") as u8;
+        regs.sp = field(initial, "sp") as u16;
+        regs.pc = initial_pc.wrapping_sub(pc_offset);
+    }
+    cpu.set_ime(field(initial, "ime") != 0);
+
+    cpu.step();
+
+    let mut errors = Vec::new();
+    let regs = cpu.regs();
+    let actual = [("a", regs.a as u64), ("b", regs.b as u64), ("c", regs.c as u64), ("d", regs.d as u64),
+                  ("e", regs.e as u64), ("f", regs.f as u64), ("h", regs.h as u64), ("l", regs.l as u64),
+                  ("sp", regs.sp as u64), ("pc", regs.pc.wrapping_add(pc_offset) as u64)];
+    for (name, value) in actual {
+        if value != field(expected, name) {
+            errors.push(format!("{} is {:x}, expected {:x}", name, value, field(expected, name)));
+        }
+    }
+    if let Some(ime) = expected.get("ime").and_then(Value::as_u64) {
+        if cpu.ime() != (ime != 0) {
+            errors.push(format!("ime is {}, expected {}", cpu.ime() as u8, ime));
+        }
+    }
+
+    for entry in expected["ram"].as_array().unwrap() {
+        let address = entry[0].as_u64().unwrap() as usize;
+        let value = entry[1].as_u64().unwrap() as u8;
+        if cpu.mem.ram.memory[address] != value {
+            errors.push(format!("({:04x}) is {:02x}, expected {:02x}", address, cpu.mem.ram.memory[address], value));
+        }
+    }
+
+    let cycles = test["cycles"].as_array().unwrap();
+    let mut expected_accesses: Vec<Access> = cycles.iter().filter_map(|cycle| {
+        let cycle = cycle.as_array()?;
+        let address = cycle[0].as_u64()? as u16;
+        let data = cycle[1].as_u64()? as u8;
+        let kind = cycle[2].as_str()?;
+        if kind.contains('r') {
+            Some(Access::Read(address, data))
+        } else if kind.contains('w') {
+            Some(Access::Write(address, data))
+        } else {
+            None
+        }
+    }).collect();
+    let mut accesses = cpu.mem.accesses.clone();
+    if prefetched {
+        // Our opcode fetch is their previous instruction, their last fetch is the next opcode
+        accesses.remove(0);
+        expected_accesses.pop();
+    }
+    if accesses != expected_accesses {
+        errors.push(format!("bus activity is {:x?}, expected {:x?}", accesses, expected_accesses));
+    }
+    if cpu.get_cycle() != cycles.len() * 4 {
+        errors.push(format!("took {} cycles, expected {}", cpu.get_cycle(), cycles.len() * 4));
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join(", "))
+    }
+}
+
+fn field(state: &Value, name: &str) -> u64 {
+    state[name].as_u64().unwrap_or(0)
+}
+
+fn ram_value(state: &Value, address: u16) -> Option<u8> {
+    state["ram"].as_array()?.iter()
+                .find(|entry| entry[0].as_u64() == Some(address as u64))
+                .and_then(|entry| entry[1].as_u64())
+                .map(|value| value as u8)
+}
@@ -0,0 +1,232 @@
+// Conformance harness running the Blargg and Mooneye test ROM suites
+//
+// The ROMs are not distributed with the emulator. They are looked up
+// recursively in the directory pointed to by the RGB_TEST_ROMS environment
+// variable, or in `tests/roms` by default, and the test is skipped if the
+// directory does not exist.
+//
+// Results are detected from:
+//  - Blargg: the text written to the serial port ("Passed"/"Failed") or the
+//    result signature written in cart RAM at $A000 by the newer test ROMs.
+//  - Mooneye: the Fibonacci sequence 3/5/8/13/21/34 in B/C/D/E/H/L when the
+//    `LD B, B` debug breakpoint is executed, or 0x42 in all of them on failure.
+//
+// A summary table is printed at the end (use `--nocapture` to see it). ROMs
+// listed in `known_failures.txt` in the ROM directory, one path relative to
+// the directory per line, do not fail the test so that only regressions do.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use rgb_core::cart::Cart;
+use rgb_core::Dmg;
+
+/// Emulated time after which a ROM that did not report a result times out
+const DEFAULT_TIMEOUT_SECONDS: usize = 120;
+const CYCLES_PER_SECOND: usize = 4_194_304;
+
+const LD_B_B: u8 = 0x40;
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+
+#[derive(Clone, PartialEq)]
+enum Outcome {
+    Pass,
+    Fail(String),
+    Timeout,
+    Error(String),
+}
+
+struct TestResult {
+    name: String,
+    outcome: Outcome,
+    known_failure: bool,
+}
+
+#[test]
+fn test_roms() {
+    let directory = std::env::var("RGB_TEST_ROMS").map(PathBuf::from)
+                        .unwrap_or_else(|_| Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/roms"));
+    if !directory.is_dir() {
+        println!("Test ROM directory {:?} not found, skipping.", directory);
+        return;
+    }
+
+    let timeout = std::env::var("RGB_TEST_ROM_TIMEOUT").ok()
+                      .and_then(|seconds| seconds.parse().ok())
+                      .unwrap_or(DEFAULT_TIMEOUT_SECONDS);
+
+    let known_failures: Vec<String> = fs::read_to_string(directory.join("known_failures.txt"))
+                                          .unwrap_or_default()
+                                          .lines()
+                                          .map(|line| line.trim().to_string())
+                                          .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                                          .collect();
+
+    let mut roms = Vec::new();
+    find_roms(&directory, &mut roms);
+    roms.sort();
+
+    let results = run_all(&directory, roms, timeout * CYCLES_PER_SECOND);
+    let results: Vec<TestResult> = results.into_iter().map(|(name, outcome)| {
+        let known_failure = known_failures.contains(&name);
+        TestResult { name, outcome, known_failure }
+    }).collect();
+
+    print_summary(&results);
+
+    let regressions: Vec<&str> = results.iter()
+                                        .filter(|result| result.outcome != Outcome::Pass && !result.known_failure)
+                                        .map(|result| result.name.as_str())
+                                        .collect();
+    assert!(regressions.is_empty(), "Failing test ROMs: {:?}", regressions);
+}
+
+fn find_roms(directory: &Path, roms: &mut Vec<PathBuf>) {
+    for entry in fs::read_dir(directory).unwrap().flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            find_roms(&path, roms);
+        } else if path.extension().is_some_and(|extension| extension == "gb") {
+            roms.push(path);
+        }
+    }
+}
+
+// Run the ROMs on all the available cores
+fn run_all(directory: &Path, roms: Vec<PathBuf>, max_cycles: usize) -> Vec<(String, Outcome)> {
+    let queue = Arc::new(Mutex::new(roms.into_iter().enumerate().collect::<Vec<_>>()));
+    let results = Arc::new(Mutex::new(Vec::new()));
+    let threads = thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+
+    let workers: Vec<_> = (0..threads).map(|_| {
+        let queue = Arc::clone(&queue);
+        let results = Arc::clone(&results);
+        let directory = directory.to_path_buf();
+        thread::spawn(move || loop {
+            let next = queue.lock().unwrap().pop();
+            let Some((index, path)) = next else { break };
+
+            let name = path.strip_prefix(&directory).unwrap().to_string_lossy().replace('\\', "/");
+            let outcome = run_rom(&path, max_cycles);
+            results.lock().unwrap().push((index, name, outcome));
+        })
+    }).collect();
+
+    for worker in workers {
+        worker.join().unwrap();
+    }
+
+    let mut results = Arc::try_unwrap(results).ok().unwrap().into_inner().unwrap();
+    results.sort_by_key(|(index, _, _)| *index);
+    results.into_iter().map(|(_, name, outcome)| (name, outcome)).collect()
+}
+
+fn run_rom(path: &Path, max_cycles: usize) -> Outcome {
+    let cart = match Cart::load(&path.to_string_lossy(), None) {
+        Ok(cart) => cart,
+        Err(err) => return Outcome::Error(err.error),
+    };
+    let mut dmg = Dmg::new(cart);
+    let mut serial_length = 0;
+
+    while dmg.cpu.get_cycle() < max_cycles {
+        let opcode = dmg.cpu.mem.read(dmg.cpu.get_pc());
+        let frame = match dmg.step() {
+            Ok(frame) => frame,
+            Err(err) => return Outcome::Error(err.to_string()),
+        };
+
+        if opcode == LD_B_B {
+            if let Some(outcome) = mooneye_result(&dmg) {
+                return outcome;
+            }
+        }
+
+        if dmg.cpu.mem.serial.output.len() != serial_length {
+            serial_length = dmg.cpu.mem.serial.output.len();
+            let serial = String::from_utf8_lossy(&dmg.cpu.mem.serial.output);
+            if serial.contains("Passed") {
+                return Outcome::Pass;
+            }
+            // Wait for the end of the line to report the failed test number
+            if let Some(index) = serial.find("Failed") {
+                if serial[index..].contains('\n') {
+                    return Outcome::Fail(last_line(&serial));
+                }
+            }
+        }
+
+        if frame {
+            if let Some(outcome) = blargg_memory_result(&dmg) {
+                return outcome;
+            }
+        }
+    }
+
+    Outcome::Timeout
+}
+
+fn mooneye_result(dmg: &Dmg) -> Option<Outcome> {
+    let regs = dmg.cpu.regs();
+    let values = [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l];
+
+    if values == MOONEYE_PASS {
+        Some(Outcome::Pass)
+    } else if values == MOONEYE_FAIL {
+        Some(Outcome::Fail(String::from("failure signature")))
+    } else {
+        None
+    }
+}
+
+fn blargg_memory_result(dmg: &Dmg) -> Option<Outcome> {
+    let mem = &dmg.cpu.mem;
+    let signature = [mem.read(0xA001), mem.read(0xA002), mem.read(0xA003)];
+    let status = mem.read(0xA000);
+
+    if signature != BLARGG_SIGNATURE || status == BLARGG_RUNNING {
+        return None;
+    }
+
+    if status == 0 {
+        Some(Outcome::Pass)
+    } else {
+        let text: Vec<u8> = (0xA004..0xC000).map(|address| mem.read(address))
+                                            .take_while(|&c| c != 0)
+                                            .collect();
+        Some(Outcome::Fail(format!("status {:02x}: {}", status, last_line(&String::from_utf8_lossy(&text)))))
+    }
+}
+
+fn last_line(text: &str) -> String {
+    text.lines().map(str::trim).rfind(|line| !line.is_empty()).unwrap_or("").to_string()
+}
+
+fn print_summary(results: &[TestResult]) {
+    let width = results.iter().map(|result| result.name.len()).max().unwrap_or(0).max(4);
+
+    println!();
+    println!("{:width$} | Result  | Details", "ROM", width = width);
+    println!("{:-<width$}-+---------+--------", "", width = width);
+    for result in results {
+        let (status, details) = match &result.outcome {
+            Outcome::Pass => ("pass", String::new()),
+            Outcome::Fail(details) => ("FAIL", details.clone()),
+            Outcome::Timeout => ("TIMEOUT", String::new()),
+            Outcome::Error(details) => ("ERROR", details.clone()),
+        };
+        let known = if result.known_failure && result.outcome != Outcome::Pass { " (known failure)" } else { "" };
+        let fixed = if result.known_failure && result.outcome == Outcome::Pass { " (listed as known failure)" } else { "" };
+        println!("{:width$} | {:7} | {}{}{}", result.name, status, details, known, fixed, width = width);
+    }
+
+    let passed = results.iter().filter(|result| result.outcome == Outcome::Pass).count();
+    println!();
+    println!("{}/{} test ROMs passed", passed, results.len());
+}
@@ -0,0 +1,92 @@
+// Scripted joypad input
+//
+// A script contains one event per line: `<frame> <button> <press|release>`.
+// Buttons are named up, down, left, right, a, b, start and select. Empty
+// lines and lines starting with '#' are ignored. For example:
+//
+//   # Skip the title screen
+//   120 start press
+//   125 start release
+
+use rgb_core::joypad::JoypadButton;
+
+pub struct InputEvent {
+    pub frame: usize,
+    pub button: JoypadButton,
+    pub pressed: bool,
+}
+
+pub fn load_script(path: &str) -> Result<Vec<InputEvent>, String> {
+    let text = std::fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    parse_script(&text)
+}
+
+/// Parse an input script, the returned events are sorted by frame
+pub fn parse_script(text: &str) -> Result<Vec<InputEvent>, String> {
+    let mut events = Vec::new();
+
+    for (line_number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let error = |message: &str| format!("Input script line {}: {}", line_number + 1, message);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(error("expected '<frame> <button> <press|release>'"));
+        }
+
+        let frame = fields[0].parse().map_err(|_| error("invalid frame number"))?;
+        let button = match fields[1].to_lowercase().as_str() {
+            "up" => JoypadButton::Up,
+            "down" => JoypadButton::Down,
+            "left" => JoypadButton::Left,
+            "right" => JoypadButton::Right,
+            "a" => JoypadButton::A,
+            "b" => JoypadButton::B,
+            "start" => JoypadButton::Start,
+            "select" => JoypadButton::Select,
+            _ => return Err(error("unknown button")),
+        };
+        let pressed = match fields[2].to_lowercase().as_str() {
+            "press" => true,
+            "release" => false,
+            _ => return Err(error("expected 'press' or 'release'")),
+        };
+
+        events.push(InputEvent { frame, button, pressed });
+    }
+
+    events.sort_by_key(|event| event.frame);
+    Ok(events)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_script() {
+        let events = parse_script("# Title screen\n\n125 Start release\n120 start press\n  200 a PRESS  \n").unwrap();
+        assert_eq!(events.len(), 3);
+        assert_eq!((events[0].frame, events[0].pressed), (120, true));
+        assert!(matches!(events[0].button, JoypadButton::Start));
+        assert_eq!((events[1].frame, events[1].pressed), (125, false));
+        assert!(matches!(events[2].button, JoypadButton::A));
+        assert!(parse_script("").unwrap().is_empty());
+    }
+
+    #[test]
+    fn invalid_frame() {
+        assert_eq!(parse_script("10 a press\nx10 a press").err().unwrap(), "Input script line 2: invalid frame number");
+        assert!(parse_script("-1 a press").is_err());
+        assert!(parse_script("10 a").is_err());
+    }
+
+    #[test]
+    fn invalid_button() {
+        assert_eq!(parse_script("10 turbo press").err().unwrap(), "Input script line 1: unknown button");
+        assert_eq!(parse_script("10 b hold").err().unwrap(), "Input script line 1: expected 'press' or 'release'");
+    }
+}
@@ -0,0 +1,265 @@
+// Headless runner
+//
+// Runs a ROM without display, audio or input devices, for CI and batch
+// testing. The run stops after a number of frames or when one of the
+// requested conditions is met. The exit code is 0 on success, 1 when the run
+// failed (timeout or failure output) and 2 when the ROM could not be loaded.
+
+use clap::App;
+use std::fs::File;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::process::exit;
+
+use rgb_core::bootstrap;
+use rgb_core::cart;
+use rgb_core::gdb::{GdbServer, SessionEnd};
+use rgb_core::profile::Profiler;
+use rgb_core::symbols::Symbols;
+use rgb_core::trace::Tracer;
+use rgb_core::Dmg;
+
+mod input;
+
+const EXIT_FAILURE: i32 = 1;
+const EXIT_ERROR: i32 = 2;
+
+/// Emulated screen width in pixels
+const SCREEN_WIDTH: usize = 160;
+/// Emulated screen height in pixels
+const SCREEN_HEIGHT: usize = 144;
+
+const DEFAULT_MAX_FRAMES: usize = 3600;
+
+enum Condition {
+    Pc(u16),
+    Serial(String),
+    Memory(u16, u8),
+}
+
+enum Outcome {
+    Success(String),
+    Failure(String),
+}
+
+fn main() {
+    let matches = App::new("rgb-headless")
+                          .about("Headless Gameboy emulator runner")
+                          .args_from_usage(
+                              "-b, --bootstrap=[bootstrap]  'Custom bootstrap rom'
+                              -s, --save=[save]             'Use a cartrige ram save file'
+                              -f, --frames=[frames]         'Maximum number of frames to run (default 3600)'
+                              --until-pc=[address]          'Succeed when PC reaches the address (hex)'
+                              --until-serial=[text]         'Succeed when the serial output contains the text'
+                              --until-mem=[address_value]   'Succeed when memory matches, as <address>=<value> (hex)'
+                              --fail-serial=[text]          'Fail when the serial output contains the text'
+                              -i, --input=[script]          'Joypad input script'
+                              --screenshot=[file]           'Write the final screen to a PPM image'
+                              --serial=[file]               'Write the serial output to a file, its last 64KiB at most'
+                              --dump-mem=[file]             'Write the final 64KiB memory space to a file'
+                              --symbols=[file]              'Debug symbols (RGBDS or no$gmb .sym) for the trace log'
+                              --profile=[file]              'Write an execution profile report to a file'
+                              --profile-stacks=[file]       'Write the profiled call stacks to a file, in the collapsed flame graph format'
+                              --coverage=[file]             'Write the ROM coverage map to a file, one flags byte per ROM byte'
+                              --coverage-summary=[file]     'Write the coverage summary per bank to a file'
+                              --trace=[file]                'Log executed instructions to a file (Gameboy Doctor format)'
+                              --trace-cycles                'Add the clock cycle count to the trace log'
+                              --trace-symbols               'Add the label of PC to the trace log, as a comment'
+                              --gdb=[port]                  'Wait for a GDB client on the localhost port before running'
+                              <ROM>                         'Gamboy rom to run'")
+                          .get_matches();
+
+    let bootstrap = match matches.value_of("bootstrap") {
+        Some(path) => bootstrap::Bootstrap::load(path).unwrap_or_else(|err| {
+            error(&format!("Error reading bootstrap: {}", err.error))
+        }),
+        None => bootstrap::Bootstrap::create_default(),
+    };
+
+    let ram_path = matches.value_of("save").map(|s| s.to_string());
+    let cart = cart::Cart::load(matches.value_of("ROM").unwrap(), ram_path.as_ref())
+                   .unwrap_or_else(|err| error(&format!("Error reading rom or save: {}", err.error)));
+
+    let max_frames = match matches.value_of("frames") {
+        Some(frames) => frames.parse().unwrap_or_else(|_| error("Invalid number of frames")),
+        None => DEFAULT_MAX_FRAMES,
+    };
+
+    let mut conditions = Vec::new();
+    if let Some(address) = matches.value_of("until-pc") {
+        conditions.push(Condition::Pc(parse_hex(address)));
+    }
+    if let Some(text) = matches.value_of("until-serial") {
+        conditions.push(Condition::Serial(text.to_string()));
+    }
+    if let Some(condition) = matches.value_of("until-mem") {
+        let (address, value) = condition.split_once('=')
+                                        .unwrap_or_else(|| error("Memory condition must be <address>=<value>"));
+        conditions.push(Condition::Memory(parse_hex(address), parse_hex(value)));
+    }
+
+    let script = match matches.value_of("input") {
+        Some(path) => input::load_script(path).unwrap_or_else(|err| error(&err)),
+        None => Vec::new(),
+    };
+
+    let mut dmg = Dmg::new_with_bootstrap(cart, bootstrap);
+
+    if let Some(path) = matches.value_of("symbols") {
+        let symbols = Symbols::load(path).unwrap_or_else(|err| error(&format!("Error reading symbols: {}", err.error)));
+        dmg.set_symbols(Some(symbols));
+    }
+
+    if matches.is_present("coverage") || matches.is_present("coverage-summary") {
+        dmg.enable_coverage();
+    }
+    if matches.is_present("profile") || matches.is_present("profile-stacks") {
+        dmg.set_profiler(Some(Profiler::new()));
+    }
+
+    if let Some(path) = matches.value_of("trace") {
+        let file = File::create(path).unwrap_or_else(|err| error(&format!("{}: {}", path, err)));
+        let mut tracer = Tracer::new(BufWriter::new(file)).with_cycles(matches.is_present("trace-cycles"));
+        if let (true, Some(symbols)) = (matches.is_present("trace-symbols"), dmg.symbols()) {
+            tracer = tracer.with_symbols(symbols.clone());
+        }
+        dmg.set_tracer(Some(tracer));
+    }
+
+    if let Some(port) = matches.value_of("gdb") {
+        let port = port.parse().unwrap_or_else(|_| error("Invalid GDB port"));
+        let server = GdbServer::bind(port).unwrap_or_else(|err| error(&format!("GDB server: {}", err)));
+        println!("Waiting for GDB on port {}", port);
+        match server.serve(&mut dmg) {
+            Ok(SessionEnd::Killed) => exit(EXIT_FAILURE),
+            Ok(_) => (),
+            Err(err) => error(&format!("GDB server: {}", err)),
+        }
+    }
+
+    let outcome = run(&mut dmg, max_frames, &conditions, matches.value_of("fail-serial"), script);
+
+    if let Some(tracer) = dmg.set_tracer(None) {
+        tracer.finish().unwrap_or_else(|err| error(&format!("Error writing trace log: {}", err)));
+    }
+
+    if let Some(path) = matches.value_of("screenshot") {
+        write_file(path, &screenshot(dmg.borrow_display()));
+    }
+    if let Some(path) = matches.value_of("serial") {
+        write_file(path, &dmg.cpu.mem.serial.output);
+    }
+    if let Some(path) = matches.value_of("profile") {
+        write_file(path, dmg.profiler().unwrap().report(dmg.symbols()).as_bytes());
+    }
+    if let Some(path) = matches.value_of("profile-stacks") {
+        write_file(path, dmg.profiler().unwrap().collapsed_stacks(dmg.symbols()).as_bytes());
+    }
+    if let Some(path) = matches.value_of("coverage") {
+        write_file(path, dmg.coverage().unwrap().rom());
+    }
+    if let Some(path) = matches.value_of("coverage-summary") {
+        write_file(path, dmg.coverage().unwrap().summary().as_bytes());
+    }
+    if let Some(path) = matches.value_of("dump-mem") {
+        let memory: Vec<u8> = (0..=0xffff).map(|address| dmg.cpu.mem.read(address)).collect();
+        write_file(path, &memory);
+    }
+
+    match outcome {
+        Outcome::Success(reason) => println!("Success: {}", reason),
+        Outcome::Failure(reason) => {
+            println!("Failure: {}", reason);
+            match dmg.location(dmg.cpu.get_pc()) {
+                Some(location) => println!("PC: {:04X} ({})", dmg.cpu.get_pc(), location),
+                None => println!("PC: {:04X}", dmg.cpu.get_pc()),
+            }
+            dmg.cpu.print_regs();
+            dmg.print_backtrace();
+            exit(EXIT_FAILURE);
+        },
+    }
+}
+
+fn run(dmg: &mut Dmg, max_frames: usize, conditions: &[Condition], fail_serial: Option<&str>,
+       script: Vec<input::InputEvent>) -> Outcome {
+    let mut script = script.into_iter().peekable();
+    let mut frame = 0;
+    let mut serial_length = 0;
+
+    loop {
+        while let Some(event) = script.next_if(|event| event.frame <= frame) {
+            dmg.set_button(event.button, event.pressed);
+        }
+
+        let frame_ready = match dmg.step() {
+            Ok(frame_ready) => frame_ready,
+            Err(err) => return Outcome::Failure(format!("emulation error at frame {}: {}", frame, err)),
+        };
+        if frame_ready {
+            frame += 1;
+            if frame >= max_frames {
+                return if conditions.is_empty() {
+                    Outcome::Success(format!("ran {} frames", frame))
+                } else {
+                    Outcome::Failure(format!("no condition met after {} frames", frame))
+                };
+            }
+        }
+
+        if dmg.cpu.mem.serial.output.len() != serial_length {
+            serial_length = dmg.cpu.mem.serial.output.len();
+            let serial = String::from_utf8_lossy(&dmg.cpu.mem.serial.output);
+
+            if let Some(text) = fail_serial.filter(|text| serial.contains(text)) {
+                return Outcome::Failure(format!("serial output contains {:?}", text));
+            }
+            for condition in conditions {
+                if let Condition::Serial(text) = condition {
+                    if serial.contains(text.as_str()) {
+                        return Outcome::Success(format!("serial output contains {:?} at frame {}", text, frame));
+                    }
+                }
+            }
+        }
+
+        for condition in conditions {
+            match condition {
+                Condition::Pc(address) if dmg.cpu.get_pc() == *address => {
+                    return Outcome::Success(format!("PC reached {:04X} at frame {}", address, frame));
+                },
+                Condition::Memory(address, value) if dmg.cpu.mem.read(*address) == *value => {
+                    return Outcome::Success(format!("memory at {:04X} is {:02X} at frame {}", address, value, frame));
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+// Convert the BGR display buffer to a binary PPM image
+fn screenshot(screen: &[u8]) -> Vec<u8> {
+    let mut image = format!("P6\n{} {}\n255\n", SCREEN_WIDTH, SCREEN_HEIGHT).into_bytes();
+    for pixel in screen.chunks(3) {
+        image.extend_from_slice(&[pixel[2], pixel[1], pixel[0]]);
+    }
+    image
+}
+
+// Parse a hex value, which has to fit in the target type
+fn parse_hex<T: TryFrom<u64>>(value: &str) -> T {
+    let digits = value.trim_start_matches('$').trim_start_matches("0x");
+    u64::from_str_radix(digits, 16).ok()
+        .and_then(|parsed| T::try_from(parsed).ok())
+        .unwrap_or_else(|| error(&format!("Invalid hex value: {}", value)))
+}
+
+fn write_file(filename: &str, data: &[u8]) {
+    let mut f = File::create(filename).unwrap_or_else(|err| error(&format!("{}: {}", filename, err)));
+    f.write_all(data).unwrap_or_else(|err| error(&format!("{}: {}", filename, err)));
+}
+
+fn error(message: &str) -> ! {
+    eprintln!("{}", message);
+    exit(EXIT_ERROR);
+}
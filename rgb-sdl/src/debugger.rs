@@ -0,0 +1,231 @@
+// Terminal debugger prompt
+//
+// Opened when the emulation stops on a breakpoint, a watchpoint or a step.
+// Addresses and values are in hexadecimal, optionally prefixed with '$' or
+// '0x', addresses can also be labels when debug symbols are loaded. Type
+// 'help' at the prompt for the list of commands.
+
+use std::io::{self, Write};
+
+use rgb_core::bus::Bus;
+use rgb_core::debugger::{Breakpoint, Comparison, Condition, Register, Step, StopReason, WatchKind, Watchpoint};
+use rgb_core::disasm;
+use rgb_core::Dmg;
+
+const HELP: &str = "\
+c, continue                        Resume the emulation
+s, step                            Step one instruction
+n, next                            Step over calls
+f, finish                          Run until the current function returns
+b, break <addr> [if <reg> <op> <value>]
+                                   Add a breakpoint, for example 'b 150 if a == 3'
+                                   or 'b Main'
+d, delete <addr>                   Delete the breakpoints at an address
+w, watch <start>[-<end>] [r|w|rw]  Add a watchpoint, on writes by default
+dw <index>                         Delete a watchpoint
+i, info                            List breakpoints and watchpoints
+r, regs                            Print the registers
+bt, backtrace                      Print the call stack
+l, list [addr] [count]             Disassemble instructions
+x <addr> [count]                   Dump memory
+q, quit                            Quit the emulator";
+
+/// Prompt for debugger commands until the emulation is resumed
+///
+/// Returns `false` if the user asked to quit.
+pub fn prompt(dmg: &mut Dmg, reason: StopReason) -> bool {
+    let location = match reason {
+        StopReason::Watchpoint { address, .. } => dmg.location(address),
+        _ => None,
+    };
+    match location {
+        Some(location) => println!("{} <{}>", reason, location),
+        None => println!("{}", reason),
+    }
+    print_location(dmg);
+
+    loop {
+        print!("(rgb) ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        if io::stdin().read_line(&mut line).unwrap_or(0) == 0 {
+            return false;
+        }
+        let args: Vec<&str> = line.split_whitespace().collect();
+        let Some(&command) = args.first() else { continue };
+
+        let result = match command {
+            "c" | "continue" => return true,
+            "s" | "step" => { dmg.debug_step(Step::Into); return true },
+            "n" | "next" => { dmg.debug_step(Step::Over); return true },
+            "f" | "finish" => { dmg.debug_step(Step::Out); return true },
+            "q" | "quit" => return false,
+            "b" | "break" => add_breakpoint(dmg, &args[1..]),
+            "d" | "delete" => delete_breakpoint(dmg, &args[1..]),
+            "w" | "watch" => add_watchpoint(dmg, &args[1..]),
+            "dw" => delete_watchpoint(dmg, &args[1..]),
+            "i" | "info" => { print_info(dmg); Ok(()) },
+            "r" | "regs" => { print_location(dmg); Ok(()) },
+            "bt" | "backtrace" => { dmg.print_backtrace(); Ok(()) },
+            "l" | "list" => list(dmg, &args[1..]),
+            "x" => dump(dmg, &args[1..]),
+            "h" | "help" => { println!("{}", HELP); Ok(()) },
+            _ => Err(format!("Unknown command '{}', type 'help' for the list of commands", command)),
+        };
+
+        if let Err(err) = result {
+            println!("{}", err);
+        }
+    }
+}
+
+fn print_location(dmg: &Dmg) {
+    dmg.cpu.print_regs();
+    print_instruction(dmg, dmg.cpu.get_pc());
+}
+
+// Print the instruction at an address, returns its length
+fn print_instruction(dmg: &Dmg, address: u16) -> u16 {
+    let (instruction, length) = disasm::disassemble(&dmg.cpu.mem, address);
+    let label = dmg.location(address).map(|location| format!(" <{}>", location)).unwrap_or_default();
+    match dmg.symbols() {
+        Some(symbols) => println!("${:04x}{}: {}", address, label, instruction.with_symbols(symbols, dmg.cpu.mem.cart.rom_bank())),
+        None => println!("${:04x}: {}", address, instruction),
+    }
+    length
+}
+
+fn print_info(dmg: &Dmg) {
+    for breakpoint in dmg.breakpoints() {
+        println!("Breakpoint {}", breakpoint);
+    }
+    for (index, watchpoint) in dmg.watchpoints().iter().enumerate() {
+        println!("Watchpoint {}: ${:04x}-${:04x} {:?}", index, watchpoint.start, watchpoint.end, watchpoint.kind);
+    }
+}
+
+fn add_breakpoint(dmg: &mut Dmg, args: &[&str]) -> Result<(), String> {
+    let (bank, address) = parse_location(dmg, args.first().ok_or("Missing address")?)?;
+    let condition = match args.get(1..) {
+        Some(["if", register, comparison, value]) => Some(Condition {
+            register: parse_register(register)?,
+            comparison: parse_comparison(comparison)?,
+            value: parse_hex(value)?,
+        }),
+        Some([]) => None,
+        _ => return Err(String::from("Expected 'break <addr> [if <reg> <op> <value>]'")),
+    };
+
+    let breakpoint = Breakpoint { address, bank, condition };
+    println!("Breakpoint {}", breakpoint);
+    dmg.add_breakpoint(breakpoint);
+    Ok(())
+}
+
+fn delete_breakpoint(dmg: &mut Dmg, args: &[&str]) -> Result<(), String> {
+    let address = parse_address(dmg, args.first().ok_or("Missing address")?)?;
+    if !dmg.remove_breakpoint(address) {
+        return Err(format!("No breakpoint at ${:04x}", address));
+    }
+    Ok(())
+}
+
+fn add_watchpoint(dmg: &mut Dmg, args: &[&str]) -> Result<(), String> {
+    let range = args.first().ok_or("Missing address")?;
+    let (start, end) = match range.split_once('-') {
+        Some((start, end)) => (parse_address(dmg, start)?, parse_address(dmg, end)?),
+        None => (parse_address(dmg, range)?, parse_address(dmg, range)?),
+    };
+    let kind = match args.get(1).copied() {
+        None | Some("w") => WatchKind::Write,
+        Some("r") => WatchKind::Read,
+        Some("rw") => WatchKind::ReadWrite,
+        Some(kind) => return Err(format!("Invalid watchpoint kind '{}', expected r, w or rw", kind)),
+    };
+
+    dmg.add_watchpoint(Watchpoint { start, end, kind });
+    Ok(())
+}
+
+fn delete_watchpoint(dmg: &mut Dmg, args: &[&str]) -> Result<(), String> {
+    let index = args.first().and_then(|index| index.parse().ok()).ok_or("Missing watchpoint index")?;
+    dmg.remove_watchpoint(index).map(|_| ()).ok_or(format!("No watchpoint {}", index))
+}
+
+fn list(dmg: &Dmg, args: &[&str]) -> Result<(), String> {
+    let mut address = match args.first() {
+        Some(address) => parse_address(dmg, address)?,
+        None => dmg.cpu.get_pc(),
+    };
+    let count = args.get(1).map(|count| count.parse().map_err(|_| "Invalid count")).transpose()?.unwrap_or(10);
+
+    for _ in 0..count {
+        address = address.wrapping_add(print_instruction(dmg, address));
+    }
+    Ok(())
+}
+
+fn dump(dmg: &Dmg, args: &[&str]) -> Result<(), String> {
+    let start = parse_address(dmg, args.first().ok_or("Missing address")?)?;
+    let count: u16 = args.get(1).map(|count| parse_hex(count)).transpose()?.unwrap_or(0x40);
+
+    for line in (0..count).step_by(16) {
+        let address = start.wrapping_add(line);
+        let bytes: Vec<String> = (0..16.min(count - line))
+            .map(|offset| format!("{:02x}", dmg.cpu.mem.peek(address.wrapping_add(offset))))
+            .collect();
+        println!("${:04x}: {}", address, bytes.join(" "));
+    }
+    Ok(())
+}
+
+// Label or hex address
+fn parse_address(dmg: &Dmg, value: &str) -> Result<u16, String> {
+    parse_location(dmg, value).map(|(_, address)| address)
+}
+
+/// Label or hex address, with the ROM bank of a label
+pub fn parse_location(dmg: &Dmg, value: &str) -> Result<(Option<u16>, u16), String> {
+    match dmg.symbols().and_then(|symbols| symbols.find(value)) {
+        Some((bank, address)) => Ok((Some(bank), address)),
+        None => parse_hex(value).map(|address| (None, address)),
+    }
+}
+
+fn parse_hex(value: &str) -> Result<u16, String> {
+    let digits = value.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid hex value '{}'", value))
+}
+
+fn parse_register(name: &str) -> Result<Register, String> {
+    Ok(match name.to_lowercase().as_str() {
+        "a" => Register::A,
+        "b" => Register::B,
+        "c" => Register::C,
+        "d" => Register::D,
+        "e" => Register::E,
+        "f" => Register::F,
+        "h" => Register::H,
+        "l" => Register::L,
+        "af" => Register::AF,
+        "bc" => Register::BC,
+        "de" => Register::DE,
+        "hl" => Register::HL,
+        "sp" => Register::SP,
+        "pc" => Register::PC,
+        _ => return Err(format!("Unknown register '{}'", name)),
+    })
+}
+
+fn parse_comparison(operator: &str) -> Result<Comparison, String> {
+    Ok(match operator {
+        "==" => Comparison::Equal,
+        "!=" => Comparison::NotEqual,
+        "<" => Comparison::Less,
+        "<=" => Comparison::LessOrEqual,
+        ">" => Comparison::Greater,
+        ">=" => Comparison::GreaterOrEqual,
+        _ => return Err(format!("Unknown comparison '{}'", operator)),
+    })
+}
@@ -26,8 +26,8 @@ impl<'a> Display<'a> {
         sdl.timer().unwrap();
 
         let mut window_builder = video.window("rgb",
-                                            (SCREEN_WIDTH as usize) as u32,
-                                            (SCREEN_HEIGHT as usize) as u32);
+                                            SCREEN_WIDTH as u32,
+                                            SCREEN_HEIGHT as u32);
         let window = window_builder.position_centered().build().unwrap();
 
         let renderer = window.renderer().accelerated().present_vsync().build().unwrap();
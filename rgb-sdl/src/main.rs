@@ -2,7 +2,7 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-use clap::App;
+use clap::{App, Arg};
 
 extern crate sdl2;
 use rgb_core::Dmg;
@@ -18,24 +18,43 @@ use sdl2::audio::AudioSpecDesired;
 extern crate rgb_core;
 use rgb_core::bootstrap;
 use rgb_core::cart;
+use rgb_core::debugger::{Breakpoint, Step, StopReason};
 use rgb_core::joypad;
 use rgb_core::mem;
+use rgb_core::profile::Profiler;
+use rgb_core::symbols::Symbols;
+use rgb_core::trace::Tracer;
 
+mod debugger;
 mod display;
 
+/// Number of frames that can be rewound (10 seconds)
+const REWIND_FRAMES: usize = 600;
+
 fn main() {
     let matches = App::new("rgb")
                           .about("Gameboy emulator")
                           .args_from_usage(
                               "-b, --bootstrap=[bootstrap] 'Custom bootstrap rom'
                               -s, --save=[save]  'Use a cartrige ram save file'
+                              --symbols=[file]   'Debug symbols (RGBDS or no$gmb .sym), <ROM>.sym is loaded by default'
+                              --profile=[file]   'Write an execution profile report to a file on exit'
+                              --profile-stacks=[file] 'Write the profiled call stacks to a file on exit, in the collapsed flame graph format'
+                              --coverage=[file]  'Write the ROM coverage map to a file on exit, one flags byte per ROM byte'
+                              --coverage-summary=[file] 'Write the coverage summary per bank to a file on exit'
+                              --trace=[file]     'Log executed instructions to a file, F11 toggles logging'
+                              --trace-cycles     'Add the clock cycle count to the trace log'
+                              --trace-symbols    'Add the label of PC to the trace log, as a comment'
                               <ROM>              'Gamboy rom to run'")
+                          .arg(Arg::new("break").long("break").value_name("address")
+                                   .takes_value(true).multiple_occurrences(true)
+                                   .help("Stop in the debugger at the address (hex or label), can be repeated"))
                           .get_matches();
 
     let bootstrap;
     if let Some(bootstrap_path) = matches.value_of("bootstrap") {
         println!("Loading bootstrap {:?}", bootstrap_path);
-        bootstrap = match bootstrap::Bootstrap::load(&bootstrap_path.to_string()) {
+        bootstrap = match bootstrap::Bootstrap::load(bootstrap_path) {
             Ok(b) => b,
             Err(err) => {
                 println!("Error reading bootstrap: {}", err.error);
@@ -56,7 +75,7 @@ fn main() {
         println!("Using save file {:?}", path);
     }
 
-    let cart = cart::Cart::load(&rom_path.to_string(), ram_path.map(|s| s.to_string()).as_ref());
+    let cart = cart::Cart::load(rom_path, ram_path.map(|s| s.to_string()).as_ref());
 
     match cart {
         Ok(_) => (),
@@ -65,7 +84,12 @@ fn main() {
             return;
         },
     }
-    let cart = cart.unwrap();
+    let mut cart = cart.unwrap();
+
+    // The cart clock keeps following the host time across save states
+    if let Some(rtc) = cart.rtc_mut() {
+        rtc.set_host_sync(true);
+    }
 
     println!("Loaded cartridge:\n{}", cart);
 
@@ -75,19 +99,88 @@ fn main() {
 
     let mut dmg = Dmg::new_with_bootstrap(cart, bootstrap);
 
+    let default_symbols_path = std::path::Path::new(rom_path).with_extension("sym");
+    let symbols_path = match matches.value_of("symbols") {
+        Some(path) => Some(path.to_string()),
+        None => default_symbols_path.exists().then(|| default_symbols_path.to_string_lossy().into_owned()),
+    };
+    if let Some(path) = symbols_path {
+        match Symbols::load(&path) {
+            Ok(symbols) => {
+                println!("Loaded {} symbols from {:?}", symbols.len(), path);
+                dmg.set_symbols(Some(symbols));
+            },
+            Err(err) => {
+                println!("Error reading symbols: {}", err.error);
+                return;
+            },
+        }
+    }
+
+    for address in matches.values_of("break").into_iter().flatten() {
+        match debugger::parse_location(&dmg, address) {
+            Ok((bank, address)) => dmg.add_breakpoint(Breakpoint { address, bank, condition: None }),
+            Err(_) => {
+                println!("Invalid breakpoint address {:?}", address);
+                return;
+            },
+        }
+    }
+
+    if matches.is_present("coverage") || matches.is_present("coverage-summary") {
+        dmg.enable_coverage();
+    }
+    if matches.is_present("profile") || matches.is_present("profile-stacks") {
+        dmg.set_profiler(Some(Profiler::new()));
+    }
+
+    let trace = TraceOptions {
+        path: matches.value_of("trace").unwrap_or("trace.log"),
+        cycles: matches.is_present("trace-cycles"),
+        symbols: matches.is_present("trace-symbols"),
+    };
+    if matches.is_present("trace") {
+        toggle_trace(&mut dmg, &trace, false);
+    }
+
     println!("Starting execution.");
     dmg.reset();
-    emulator_loop(&mut dmg, disp, sdl);
+    let state_path = format!("{}.state", rom_path);
+    emulator_loop(&mut dmg, disp, sdl, &state_path, &trace);
+
+    if let Some(tracer) = dmg.set_tracer(None) {
+        if let Err(err) = tracer.finish() {
+            println!("Error writing trace log: {}", err);
+        }
+    }
+
+    if let Some(path) = matches.value_of("coverage") {
+        println!("Writing ROM coverage map to {:?}", path);
+        dump_ram(path, dmg.coverage().unwrap().rom());
+    }
+    if let Some(path) = matches.value_of("coverage-summary") {
+        println!("Writing coverage summary to {:?}", path);
+        dump_ram(path, dmg.coverage().unwrap().summary().as_bytes());
+    }
+    if let Some(path) = matches.value_of("profile") {
+        println!("Writing profile report to {:?}", path);
+        dump_ram(path, dmg.profiler().unwrap().report(dmg.symbols()).as_bytes());
+    }
+    if let Some(path) = matches.value_of("profile-stacks") {
+        println!("Writing profiled call stacks to {:?}", path);
+        dump_ram(path, dmg.profiler().unwrap().collapsed_stacks(dmg.symbols()).as_bytes());
+    }
 
     if let Some(path) = ram_path {
         println!("Writing back cart ram to {:?}", path);
-        dump_ram(path, &dmg.cpu.mem.cart.ram);
+        dump_ram(path, &dmg.cpu.mem.cart.save_data());
     }
 
     println!("Exiting ...");
 }
 
-fn emulator_loop(dmg: &mut Dmg, mut disp: display::Display, sdl: Sdl) {
+fn emulator_loop(dmg: &mut Dmg, mut disp: display::Display, sdl: Sdl, state_path: &str,
+                 trace: &TraceOptions) {
     let audio_subsystem = sdl.audio().unwrap();
     let desired_spec = AudioSpecDesired {
         freq: Some(44100),
@@ -99,11 +192,37 @@ fn emulator_loop(dmg: &mut Dmg, mut disp: display::Display, sdl: Sdl) {
 
     device.resume();
 
+    dmg.enable_rewind(REWIND_FRAMES);
+    let mut rewinding = false;
+
     'outer: loop {
-        dmg.run_until_next_frame();
+        if rewinding {
+            dmg.rewind_frames(1);
+        } else {
+            loop {
+                match dmg.run_until_break() {
+                    Ok(StopReason::FrameReady) => break,
+                    Ok(reason) => {
+                        if !debugger::prompt(dmg, reason) {
+                            break 'outer;
+                        }
+                    },
+                    Err(err) => {
+                        println!("Emulation error: {}", err);
+                        dmg.cpu.print_regs();
+                        dmg.print_backtrace();
+                        break 'outer;
+                    },
+                }
+            }
+
+            for byte in dmg.cpu.mem.serial.output.drain(..) {
+                print!("\x1b[1;34m{}\x1b[0m", byte as char);
+            }
 
-        // println!("Audio samples: {}", dmg.cpu.mem.audio.audio_buffer.len());
-        device.queue(&dmg.cpu.mem.audio.audio_buffer);
+            // println!("Audio samples: {}", dmg.cpu.mem.audio.audio_buffer.len());
+            device.queue(&dmg.cpu.mem.audio.audio_buffer);
+        }
         dmg.cpu.mem.audio.audio_buffer.clear();
 
         // Display the picture!
@@ -113,6 +232,12 @@ fn emulator_loop(dmg: &mut Dmg, mut disp: display::Display, sdl: Sdl) {
             match ev {
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => break 'outer,
                 Event::Quit { .. } => break 'outer,
+                Event::KeyDown { keycode: Some(Keycode::F5), .. } => save_state(dmg, state_path),
+                Event::KeyDown { keycode: Some(Keycode::F9), .. } => load_state(dmg, state_path),
+                Event::KeyDown { keycode: Some(Keycode::Backspace), .. } => rewinding = true,
+                Event::KeyUp { keycode: Some(Keycode::Backspace), .. } => rewinding = false,
+                Event::KeyDown { keycode: Some(Keycode::F11), .. } => toggle_trace(dmg, trace, true),
+                Event::KeyDown { keycode: Some(Keycode::F12), .. } => dmg.debug_step(Step::Into),
                 _ => {}
             }
             if let Some((button, pressed)) = decode_keyboard(&ev) {
@@ -135,12 +260,59 @@ fn emulator_loop(dmg: &mut Dmg, mut disp: display::Display, sdl: Sdl) {
 
 use std::io::prelude::*;
 use std::fs::File;
+use std::io::BufWriter;
 
 fn dump_ram(filename: &str, vram: &[u8]) {
     let mut f = File::create(filename).unwrap();
     f.write_all(vram).unwrap();
 }
 
+// Trace log settings from the command line
+struct TraceOptions<'a> {
+    path: &'a str,
+    cycles: bool,
+    // Label of PC as a comment, which the Gameboy Doctor format does not have
+    symbols: bool,
+}
+
+// Start logging instructions, appending to the file or truncating it, or stop
+// logging
+fn toggle_trace(dmg: &mut Dmg, trace: &TraceOptions, append: bool) {
+    if let Some(tracer) = dmg.set_tracer(None) {
+        println!("Trace log stopped");
+        if let Err(err) = tracer.finish() {
+            println!("Error writing trace log: {}", err);
+        }
+        return;
+    }
+
+    match File::options().create(true).write(true).append(append).truncate(!append).open(trace.path) {
+        Ok(file) => {
+            println!("Logging instructions to {:?}", trace.path);
+            let mut tracer = Tracer::new(BufWriter::new(file)).with_cycles(trace.cycles);
+            if let (true, Some(symbols)) = (trace.symbols, dmg.symbols()) {
+                tracer = tracer.with_symbols(symbols.clone());
+            }
+            dmg.set_tracer(Some(tracer));
+        },
+        Err(err) => println!("Error opening trace log {:?}: {}", trace.path, err),
+    }
+}
+
+fn save_state(dmg: &Dmg, filename: &str) {
+    println!("Saving state to {:?}", filename);
+    dump_ram(filename, &dmg.save_state());
+}
+
+fn load_state(dmg: &mut Dmg, filename: &str) {
+    println!("Loading state from {:?}", filename);
+    let result = std::fs::read(filename).map_err(|err| err.to_string())
+                     .and_then(|state| dmg.load_state(&state).map_err(|err| err.error));
+    if let Err(err) = result {
+        println!("Error loading state: {}", err);
+    }
+}
+
 fn dump_memory_space(filename: &str, mem: &mem::Mem) {
     let mut f = File::create(filename).unwrap();
     for addr in 0 .. 65536 {